@@ -0,0 +1,73 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::commit_info::CommitInfo;
+use crate::SETTINGS;
+
+fn open(url: &str) -> bool {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    Command::new(opener)
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+fn copy(text: &str) -> bool {
+    let clipboards: [(&str, &[&str]); 3] = [
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+        ("pbcopy", &[]),
+    ];
+    for (cmd, args) in clipboards {
+        let child = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            let _ = child.stdin.take().unwrap().write_all(text.as_bytes());
+            if child.wait().map(|s| s.success()).unwrap_or(false) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+pub fn copy_text(text: &str) {
+    if !copy(text) {
+        println!("Failed to copy, install wl-copy, xclip or pbcopy");
+    }
+}
+
+pub fn show(commits: &[&CommitInfo]) {
+    let urls: Vec<&str> = commits
+        .iter()
+        .map(|c| c.get_url())
+        .filter(|u| !u.is_empty())
+        .collect();
+    if urls.len() < commits.len() {
+        println!("Could not find the url of all commits");
+    }
+    if SETTINGS.copy_url {
+        copy_text(&urls.join("\n"));
+    } else if SETTINGS.print_url || SETTINGS.dry_run {
+        for url in urls {
+            println!("{}", url);
+        }
+    } else {
+        for url in urls {
+            if !open(url) {
+                println!("Failed to open {}", url);
+            }
+        }
+    }
+}
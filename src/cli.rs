@@ -0,0 +1,692 @@
+use getopts::{HasArg, Occur, Options};
+
+// Options are grouped by what they affect, each command supports a set of groups
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Global,
+    Query,
+    Picker,
+    Run,
+    Download,
+    Branch,
+    Worktree,
+    Patch,
+    Open,
+}
+
+pub struct Opt {
+    pub short: &'static str,
+    pub long: &'static str,
+    pub hint: &'static str,
+    pub desc: &'static str,
+    has_arg: HasArg,
+    occur: Occur,
+    group: Group,
+}
+
+const fn flag(short: &'static str, long: &'static str, desc: &'static str, group: Group) -> Opt {
+    Opt {
+        short,
+        long,
+        hint: "",
+        desc,
+        has_arg: HasArg::No,
+        occur: Occur::Optional,
+        group,
+    }
+}
+
+const fn opt(
+    short: &'static str,
+    long: &'static str,
+    desc: &'static str,
+    hint: &'static str,
+    group: Group,
+) -> Opt {
+    Opt {
+        short,
+        long,
+        hint,
+        desc,
+        has_arg: HasArg::Yes,
+        occur: Occur::Optional,
+        group,
+    }
+}
+
+pub const OPTIONS: &[Opt] = &[
+    flag("h", "help", "Print this menu", Group::Global),
+    Opt {
+        occur: Occur::Multi,
+        ..flag(
+            "v",
+            "verbose",
+            "Show the full output of the commands while they run and log timings to stderr, give twice (-vv) to also log debug information. The level can also be set with GRT_LOG=info|debug",
+            Group::Global,
+        )
+    },
+    flag("", "debug", "Same as -vv", Group::Global),
+    opt(
+        "",
+        "status",
+        "Only include commits with the given status, open, merged, abandoned, closed or any (default open)",
+        "STATUS",
+        Group::Query,
+    ),
+    flag(
+        "c",
+        "closed",
+        "Include closed commits, same as --status any",
+        Group::Query,
+    ),
+    flag(
+        "o",
+        "open",
+        "Don't include closed commits, same as --status open (will override -c if set)",
+        Group::Query,
+    ),
+    flag("", "merged", "Same as --status merged", Group::Query),
+    flag("", "abandoned", "Same as --status abandoned", Group::Query),
+    opt(
+        "",
+        "owner",
+        "Only include commits owned by the given user",
+        "USER",
+        Group::Query,
+    ),
+    flag("", "mine", "Only include your own commits", Group::Query),
+    opt(
+        "",
+        "reviewer",
+        "Only include commits with the given user as reviewer",
+        "USER",
+        Group::Query,
+    ),
+    opt(
+        "",
+        "branch",
+        "Only include commits for the given branch",
+        "NAME",
+        Group::Query,
+    ),
+    flag(
+        "",
+        "upstream",
+        "Only include commits for the upstream branch of the current branch",
+        Group::Query,
+    ),
+    opt(
+        "",
+        "topic",
+        "Only include commits in the given topic",
+        "NAME",
+        Group::Query,
+    ),
+    opt(
+        "",
+        "since",
+        "Only include commits updated within the given time, e.g. 2d, 12h or 1w",
+        "AGE",
+        Group::Query,
+    ),
+    flag(
+        "",
+        "wip",
+        "Only include work in progress commits",
+        Group::Query,
+    ),
+    flag(
+        "",
+        "no-wip",
+        "Don't include work in progress commits",
+        Group::Query,
+    ),
+    flag("a", "all", "pre-select all commits", Group::Picker),
+    flag(
+        "p",
+        "show-parent",
+        "Show info about parent commit in preview even in ssh (This will lead to additional queries)",
+        Group::Picker,
+    ),
+    flag(
+        "1",
+        "first",
+        "Don't show the picker, select the most recently updated commit",
+        Group::Picker,
+    ),
+    opt(
+        "",
+        "change",
+        "Don't show the picker, select the commit with the given change number or Change-Id",
+        "CHANGE",
+        Group::Picker,
+    ),
+    opt(
+        "f",
+        "filter",
+        "Don't show the picker, select all commits fuzzy matching the given string",
+        "STR",
+        Group::Picker,
+    ),
+    Opt {
+        occur: Occur::Multi,
+        ..opt(
+            "",
+            "bind",
+            "Bind a key in the picker to an action, the action can be any of the commands or expand-topic, edit-query, copy-ref or copy-url. Can be given multiple times",
+            "KEY:ACTION",
+            Group::Picker,
+        )
+    },
+    opt(
+        "",
+        "height",
+        "Height of the picker in lines or percent of the terminal (default 50%)",
+        "HEIGHT",
+        Group::Picker,
+    ),
+    flag(
+        "",
+        "reverse",
+        "Show the prompt at the top of the picker",
+        Group::Picker,
+    ),
+    opt(
+        "",
+        "preview-window",
+        "Position and size of the preview window, e.g. 'down:40%' or 'right:50%:hidden' (default right:50%)",
+        "SPEC",
+        Group::Picker,
+    ),
+    opt(
+        "",
+        "color",
+        "Colour scheme of the picker, e.g. 'light' or 'fg:252,bg:233' (see skim --help)",
+        "SPEC",
+        Group::Picker,
+    ),
+    opt(
+        "",
+        "prompt",
+        "Prompt text of the picker (default '> ')",
+        "TEXT",
+        Group::Picker,
+    ),
+    opt(
+        "",
+        "picker",
+        "Picker to use, skim, fzf (needs fzf installed) or numbered (default skim, numbered if TERM=dumb)",
+        "NAME",
+        Group::Picker,
+    ),
+    flag(
+        "",
+        "no-multi",
+        "Only allow selecting a single commit in the picker",
+        Group::Picker,
+    ),
+    flag(
+        "n",
+        "dry-run",
+        "Only print the commands that would be run, don't execute them",
+        Group::Run,
+    ),
+    opt(
+        "",
+        "script",
+        "Write the commands that would be run to a shell script instead of running them",
+        "FILE",
+        Group::Run,
+    ),
+    flag(
+        "y",
+        "yes",
+        "Don't ask for confirmation, answer yes to all questions but stop at the first failed command",
+        Group::Run,
+    ),
+    opt(
+        "j",
+        "jobs",
+        "Number of projects to download in parallel in a repo workspace (default 1)",
+        "N",
+        Group::Run,
+    ),
+    flag(
+        "t",
+        "with-topic",
+        "Also download the other open commits in the topics of the selected commits",
+        Group::Download,
+    ),
+    flag(
+        "s",
+        "stash",
+        "Stash uncommitted changes before downloading and restore them afterwards",
+        Group::Download,
+    ),
+    flag(
+        "",
+        "allow-dirty",
+        "Download even if there are uncommitted changes",
+        Group::Download,
+    ),
+    opt(
+        "b",
+        "local-branch",
+        "Checkout into the given local branch, created or reset to the downloaded commit",
+        "NAME",
+        Group::Branch,
+    ),
+    flag(
+        "B",
+        "review-branch",
+        "Same as --local-branch review/<number>-<patchset>",
+        Group::Branch,
+    ),
+    opt(
+        "w",
+        "worktree",
+        "Checkout into a separate git worktree at the given path",
+        "PATH",
+        Group::Worktree,
+    ),
+    flag(
+        "W",
+        "new-worktree",
+        "Same as --worktree <toplevel>-<number>",
+        Group::Worktree,
+    ),
+    opt(
+        "",
+        "patch-dir",
+        "Directory to write patches to (default 'patches')",
+        "DIR",
+        Group::Patch,
+    ),
+    flag(
+        "",
+        "print-url",
+        "Print the url instead of opening it in the browser",
+        Group::Open,
+    ),
+    flag(
+        "",
+        "copy-url",
+        "Copy the url to the clipboard instead of opening it in the browser",
+        Group::Open,
+    ),
+];
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub method: &'static str,
+    pub about: &'static str,
+    groups: &'static [Group],
+}
+
+const DOWNLOAD: &[Group] = &[Group::Query, Group::Picker, Group::Run, Group::Download];
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "checkout",
+        aliases: &["co"],
+        method: "Checkout",
+        about: "Check out the selected commit",
+        groups: &[
+            Group::Query,
+            Group::Picker,
+            Group::Run,
+            Group::Download,
+            Group::Branch,
+            Group::Worktree,
+        ],
+    },
+    Command {
+        name: "cherry-pick",
+        aliases: &["cp"],
+        method: "Cherry-Pick",
+        about: "Cherry-pick the selected commits onto the current branch",
+        groups: DOWNLOAD,
+    },
+    Command {
+        name: "rebase",
+        aliases: &["rb"],
+        method: "Rebase",
+        about: "Rebase the current branch onto the selected commit or stack",
+        groups: DOWNLOAD,
+    },
+    Command {
+        name: "merge",
+        aliases: &[],
+        method: "Merge",
+        about: "Merge the selected commits with git merge --no-ff",
+        groups: DOWNLOAD,
+    },
+    Command {
+        name: "fetch",
+        aliases: &["fetch-only"],
+        method: "Fetch",
+        about: "Only fetch the selected commits into local branches",
+        groups: &[
+            Group::Query,
+            Group::Picker,
+            Group::Run,
+            Group::Download,
+            Group::Branch,
+        ],
+    },
+    Command {
+        name: "patch",
+        aliases: &["format-patch"],
+        method: "Patch",
+        about: "Write the selected commits as patch files with git format-patch",
+        groups: &[
+            Group::Query,
+            Group::Picker,
+            Group::Run,
+            Group::Download,
+            Group::Patch,
+        ],
+    },
+    Command {
+        name: "open",
+        aliases: &[],
+        method: "Open",
+        about: "Open the selected commits in the browser",
+        groups: &[Group::Query, Group::Picker, Group::Open],
+    },
+    Command {
+        name: "list",
+        aliases: &["ls"],
+        method: "List",
+        about: "Print the commits matching the query",
+        groups: &[Group::Query],
+    },
+    Command {
+        name: "undo",
+        aliases: &[],
+        method: "Undo",
+        about: "Restore the state from before the latest checkout or cherry-pick",
+        groups: &[Group::Run],
+    },
+    Command {
+        name: "history",
+        aliases: &["log"],
+        method: "History",
+        about: "List the recorded operations that can be undone",
+        groups: &[],
+    },
+    Command {
+        name: "doctor",
+        aliases: &[],
+        method: "Doctor",
+        about: "Check the setup and the connection to the Gerrit server",
+        groups: &[],
+    },
+    Command {
+        name: "completions",
+        aliases: &[],
+        method: "Completions",
+        about: "Print a completion script for bash, zsh or fish",
+        groups: &[],
+    },
+    Command {
+        name: "help",
+        aliases: &[],
+        method: "Help",
+        about: "Show the options of a command",
+        groups: &[],
+    },
+];
+
+// Used by the completion scripts to complete query terms, not shown in the help
+const COMPLETE: Command = Command {
+    name: "__complete",
+    aliases: &[],
+    method: "Complete",
+    about: "Print the query terms starting with the given word",
+    groups: &[],
+};
+
+impl Command {
+    pub fn has(&self, opt: &Opt) -> bool {
+        opt.group == Group::Global || self.groups.contains(&opt.group)
+    }
+
+    // If the command can be bound to a key in the picker
+    pub fn uses_picker(&self) -> bool {
+        self.groups.contains(&Group::Picker)
+    }
+
+    fn options(&self) -> impl Iterator<Item = &'static Opt> + '_ {
+        OPTIONS.iter().filter(|o| self.has(o))
+    }
+
+    // Commands that take a query after the options
+    pub fn takes_query(&self) -> bool {
+        self.groups.contains(&Group::Query)
+    }
+}
+
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS
+        .iter()
+        .chain(std::iter::once(&COMPLETE))
+        .find(|c| c.name == name || c.aliases.contains(&name))
+}
+
+fn build_options<'a>(opts: impl Iterator<Item = &'a Opt>) -> Options {
+    let mut options = Options::new();
+    for o in opts {
+        options.opt(o.short, o.long, o.desc, o.hint, o.has_arg, o.occur);
+    }
+    options
+}
+
+pub fn all_options() -> Options {
+    build_options(OPTIONS.iter())
+}
+
+pub fn print_help(command: Option<&Command>, code: i32) -> ! {
+    let bin = "grt";
+    match command {
+        Some(c) => {
+            let mut brief = format!(
+                "Usage: {} {} [options]{}\n\n{}",
+                bin,
+                c.name,
+                if c.takes_query() { " [query]" } else { "" },
+                c.about
+            );
+            if !c.aliases.is_empty() {
+                brief += &format!(", can also be run as {}", c.aliases.join(" or "));
+            }
+            print!("{}", build_options(c.options()).usage(&brief));
+        }
+        None => {
+            println!("Usage: {} <command> [options] [query]", bin);
+            println!();
+            println!("Commands:");
+            for c in COMMANDS {
+                let names = std::iter::once(c.name)
+                    .chain(c.aliases.iter().copied())
+                    .collect::<Vec<&str>>()
+                    .join(", ");
+                println!("    {:<24}{}", names, c.about);
+            }
+            println!();
+            println!(
+                "Run '{} help <command>' to see the options of a command.",
+                bin
+            );
+        }
+    }
+    println!("\nThe options can be set either on command line or through");
+    println!("the env var GRT_ARGS, anything set on command line will");
+    println!("override what's set in the environment.");
+    std::process::exit(code);
+}
+
+fn names(c: &Command) -> Vec<&'static str> {
+    std::iter::once(c.name)
+        .chain(c.aliases.iter().copied())
+        .collect()
+}
+
+// Arguments other than options that a command can complete
+fn arguments(c: &Command) -> Vec<&'static str> {
+    match c.name {
+        "completions" => vec!["bash", "zsh", "fish"],
+        "help" => COMMANDS.iter().map(|c| c.name).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn bash_completions() -> String {
+    let commands: Vec<&str> = COMMANDS.iter().flat_map(names).collect();
+    let mut script = format!(
+        "_grt() {{\n    local cur=${{COMP_WORDS[COMP_CWORD]}}\n    if [ \"$COMP_CWORD\" -eq 1 ]; then\n        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n        return\n    fi\n    local words query\n    case \"${{COMP_WORDS[1]}}\" in\n",
+        commands.join(" ")
+    );
+    for c in COMMANDS {
+        let mut words: Vec<String> = c.options().map(|o| format!("--{}", o.long)).collect();
+        words.extend(arguments(c).iter().map(|a| a.to_string()));
+        script += &format!(
+            "        {}) words=\"{}\"{} ;;\n",
+            names(c).join("|"),
+            words.join(" "),
+            if c.takes_query() { "; query=1" } else { "" }
+        );
+    }
+    // Query terms contain ':', which bash splits words on, so the whole word is taken from
+    // the line and only the part after the last ':' is replaced
+    script += r#"    esac
+    local word=${COMP_LINE:0:$COMP_POINT}
+    word=${word##*[[:space:]]}
+    if [ -n "$query" ] && [[ $word != -* || $word == *:* ]]; then
+        COMPREPLY=($(grt __complete -- "$word"))
+        COMPREPLY=("${COMPREPLY[@]#"${word%"${word##*:}"}"}")
+        if [[ ${#COMPREPLY[@]} -eq 1 && ${COMPREPLY[0]} == *: ]]; then
+            compopt -o nospace
+        fi
+        return
+    fi
+    COMPREPLY=($(compgen -W "$words" -- "$cur"))
+}
+complete -F _grt grt
+"#;
+    script
+}
+
+fn zsh_completions() -> String {
+    let escape = |s: &str| s.replace('\'', "'\\''").replace(']', "\\]");
+    let mut script =
+        "#compdef grt\n\n_grt_query() {\n    local -a terms\n    terms=(${(f)\"$(grt __complete -- \"$PREFIX\")\"})\n    compadd -S '' -- ${(M)terms:#*:}\n    compadd -- ${terms:#*:}\n}\n\n_grt() {\n    local -a commands\n    commands=(\n".to_string();
+    for c in COMMANDS {
+        for n in names(c) {
+            script += &format!("        '{}:{}'\n", n, escape(c.about));
+        }
+    }
+    script += "    )\n    if (( CURRENT == 2 )); then\n        _describe 'command' commands\n        return\n    fi\n    shift words\n    (( CURRENT-- ))\n    case $words[1] in\n";
+    for c in COMMANDS {
+        script += &format!(
+            "        {})\n            _arguments \\\n",
+            names(c).join("|")
+        );
+        for o in c.options() {
+            let arg = if o.hint.is_empty() {
+                "".to_string()
+            } else {
+                format!(":{}:", o.hint)
+            };
+            let eq = if o.hint.is_empty() { "" } else { "=" };
+            script += &format!(
+                "                '--{}{}[{}]{}' \\\n",
+                o.long,
+                eq,
+                escape(o.desc),
+                arg
+            );
+            if !o.short.is_empty() {
+                script += &format!(
+                    "                '-{}[{}]{}' \\\n",
+                    o.short,
+                    escape(o.desc),
+                    arg
+                );
+            }
+        }
+        let args = arguments(c);
+        if !args.is_empty() {
+            script += &format!("                '1:argument:({})' \\\n", args.join(" "));
+        }
+        script += if c.takes_query() {
+            "                '*::query:_grt_query'\n            ;;\n"
+        } else {
+            "                '*::query:'\n            ;;\n"
+        };
+    }
+    script += "    esac\n}\n\n_grt \"$@\"\n";
+    script
+}
+
+fn fish_completions() -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('\'', "\\'");
+    let mut script = "complete -c grt -f\n".to_string();
+    for c in COMMANDS {
+        for n in names(c) {
+            script += &format!(
+                "complete -c grt -n __fish_use_subcommand -a {} -d '{}'\n",
+                n,
+                escape(c.about)
+            );
+        }
+    }
+    for c in COMMANDS {
+        let condition = format!("__fish_seen_subcommand_from {}", names(c).join(" "));
+        for o in c.options() {
+            let short = if o.short.is_empty() {
+                "".to_string()
+            } else {
+                format!(" -s {}", o.short)
+            };
+            let required = if o.hint.is_empty() { "" } else { " -r" };
+            script += &format!(
+                "complete -c grt -n '{}' -l {}{}{} -d '{}'\n",
+                condition,
+                o.long,
+                short,
+                required,
+                escape(o.desc)
+            );
+        }
+        let args = arguments(c);
+        if !args.is_empty() {
+            script += &format!(
+                "complete -c grt -n '{}' -a '{}'\n",
+                condition,
+                args.join(" ")
+            );
+        }
+        if c.takes_query() {
+            script += &format!(
+                "complete -c grt -n '{}' -a '(grt __complete -- (commandline -ct))'\n",
+                condition
+            );
+        }
+    }
+    script
+}
+
+pub fn print_completions(shell: Option<&str>) {
+    let script = match shell {
+        Some("bash") => bash_completions(),
+        Some("zsh") => zsh_completions(),
+        Some("fish") => fish_completions(),
+        _ => {
+            println!("Usage: grt completions bash|zsh|fish");
+            std::process::exit(1);
+        }
+    };
+    print!("{}", script);
+}
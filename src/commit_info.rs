@@ -1,4 +1,8 @@
-use crate::{remote::RemoteUrl, repo_info::RepoType, REPO_INFO, SETTINGS};
+use crate::{
+    remote::RemoteUrl,
+    repo_info::{RepoInfo, RepoType},
+    REPO_INFO, SETTINGS,
+};
 use skim::prelude::*;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -9,6 +13,8 @@ pub struct CommitInfo {
     author: String,
     branch: String,
     reference: String,
+    revision: String,
+    url: String,
     files: Vec<String>,
     pub topic: Option<String>,
     parent: (String, String),
@@ -16,13 +22,15 @@ pub struct CommitInfo {
 
 #[allow(clippy::too_many_arguments)]
 impl CommitInfo {
-    fn new(
+    pub fn new(
         project: &str,
         subject: &str,
         message: &str,
         author: &str,
         branch: &str,
         reference: &str,
+        revision: &str,
+        url: &str,
         files: Vec<String>,
         topic: Option<&str>,
         parent_subject: (&str, &str),
@@ -34,6 +42,8 @@ impl CommitInfo {
             author: author.to_string(),
             branch: branch.to_string(),
             reference: reference.to_string(),
+            revision: revision.to_string(),
+            url: url.to_string(),
             files,
             topic: topic.map(|s| s.to_string()),
             parent: (parent_subject.0.to_string(), parent_subject.1.to_string()),
@@ -49,6 +59,48 @@ impl CommitInfo {
             .map(|data| CommitInfo::from_json(&data))
     }
 
+    pub fn from_item(item: &Arc<dyn SkimItem>) -> &CommitInfo {
+        (**item)
+            .as_any()
+            .downcast_ref::<CommitInfo>()
+            .expect("Could not cast to CommitInfo")
+    }
+
+    // The commits in the topic, and those skipped because they are in other projects
+    pub fn get_topic_commits(&self) -> (Vec<CommitInfo>, Vec<CommitInfo>) {
+        match &self.topic {
+            Some(topic) => {
+                let commits = REPO_INFO
+                    .remote_url
+                    .perform_query(&SETTINGS.topic_query(topic, &self.branch));
+                let commits: Vec<CommitInfo> = CommitInfo::parse_json(&commits).collect();
+                if REPO_INFO.repo_type != RepoType::Git || REPO_INFO.project_name.is_empty() {
+                    return (commits, Vec::new());
+                }
+                // Commits in other projects can't be fetched into this repository
+                commits
+                    .into_iter()
+                    .partition(|c| c.project == REPO_INFO.project_name)
+            }
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+
+    pub fn print_skipped(skipped: &[CommitInfo]) {
+        if skipped.is_empty() {
+            return;
+        }
+        println!("Skipping the following topic commit(s) from other projects:");
+        for c in skipped {
+            println!(
+                "* {} - {} [{}]",
+                c.project,
+                c.subject,
+                c.topic.as_deref().unwrap_or_default()
+            );
+        }
+    }
+
     pub fn get_title(&self) -> String {
         return if REPO_INFO.repo_type == RepoType::Git {
             "".to_string()
@@ -56,11 +108,21 @@ impl CommitInfo {
             self.project.clone() + " - "
         } + &self.subject
             + " - "
-            + &self.author;
+            + &self.author
+            + &self
+                .topic
+                .as_ref()
+                .map(|t| format!(" [{}]", t))
+                .unwrap_or_default();
     }
 
     pub fn get_body(&self) -> String {
-        let parent_str = match (SETTINGS.show_parent, self.parent.0.as_str(), self.parent.1.as_str()) {
+        self.get_body_with_parent(SETTINGS.show_parent)
+    }
+
+    // query_parent looks up the parent commit on the server if it's not part of the commit data
+    pub fn get_body_with_parent(&self, query_parent: bool) -> String {
+        let parent_str = match (query_parent, self.parent.0.as_str(), self.parent.1.as_str()) {
             (_, "", _) => "".to_string(),
             (false, _, "") => "".to_string(),
             (true, hash, "") => {
@@ -90,6 +152,42 @@ Branch: {}{}
         //     + &self.files.join("\n");
     }
 
+    pub fn get_project(&self) -> &str {
+        &self.project
+    }
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+    pub fn get_revision(&self) -> &str {
+        &self.revision
+    }
+    pub fn get_parent_revision(&self) -> &str {
+        &self.parent.0
+    }
+    pub fn get_number(&self) -> &str {
+        self.reference.split('/').nth(3).unwrap_or("")
+    }
+    pub fn get_patchset(&self) -> &str {
+        self.reference.split('/').nth(4).unwrap_or("")
+    }
+
+    pub fn get_branch_name(&self) -> String {
+        if let Some(name) = &SETTINGS.branch_name {
+            return name.clone();
+        }
+        // Not named after the topic, which is set by the author and could name an existing branch
+        format!("review/{}-{}", self.get_number(), self.get_patchset())
+    }
+
+    pub fn get_worktree_path(&self) -> String {
+        if let Some(path) = &SETTINGS.worktree_path {
+            return path.clone();
+        }
+        let toplevel = RepoInfo::get_toplevel();
+        let toplevel = toplevel.trim_end_matches('/');
+        format!("{}-{}", toplevel, self.get_number())
+    }
+
     pub fn get_git_reference(&self) -> String {
         self.reference.clone()
     }
@@ -122,6 +220,8 @@ Branch: {}{}
         let reference = data["currentPatchSet"]["ref"]
             .as_str()
             .expect("Failed to find ref");
+        let revision = data["currentPatchSet"]["revision"].as_str().unwrap_or("");
+        let url = data["url"].as_str().unwrap_or("");
         let branch = data["branch"].as_str().expect("Failed to find branch");
 
         let mut files: Vec<String> = Vec::new();
@@ -142,9 +242,7 @@ Branch: {}{}
 
         let topic = data["topic"].as_str();
 
-        let parent_hash = data["currentPatchSet"]["parents"][0]
-            .as_str()
-            .unwrap_or("");
+        let parent_hash = data["currentPatchSet"]["parents"][0].as_str().unwrap_or("");
         Self::new(
             project,
             subject,
@@ -152,14 +250,29 @@ Branch: {}{}
             author,
             branch,
             reference,
+            revision,
+            url,
             files,
             topic,
             (parent_hash, ""),
         )
     }
 
+    // Older servers may leave out current_revision, newer ones also have current_revision_number
+    fn get_current_revision(data: &json::JsonValue) -> &str {
+        if let Some(revision) = data["current_revision"].as_str() {
+            return revision;
+        }
+        let number = data["current_revision_number"].as_u32();
+        data["revisions"]
+            .entries()
+            .find(|(_, r)| number.is_none() || r["_number"].as_u32() == number)
+            .map(|(hash, _)| hash)
+            .unwrap_or("")
+    }
+
     fn from_http_json(data: &json::JsonValue) -> Self {
-        let current_revision = data["current_revision"].as_str().unwrap_or("");
+        let current_revision = Self::get_current_revision(data);
         let project = data["project"]
             .as_str()
             .expect("Failed to get project name");
@@ -176,12 +289,16 @@ Branch: {}{}
             .as_str()
             .expect("Failed to find ref");
         let branch = data["branch"].as_str().expect("Failed to find branch");
+        let url = REPO_INFO
+            .remote_url
+            .web_url(project, &data["_number"].to_string());
 
         let mut files: Vec<String> = Vec::new();
         for file in data["revisions"][current_revision]["files"].entries() {
             files.push(format!(
                 "{} {} +{} -{}",
-                file.1["status"].as_str().unwrap_or(""),
+                // The status is left out for modified files
+                file.1["status"].as_str().unwrap_or("M"),
                 file.0,
                 file.1["lines_inserted"].as_i32().unwrap_or(0),
                 file.1["lines_deleted"].as_i32().unwrap_or(0)
@@ -202,6 +319,8 @@ Branch: {}{}
             author,
             branch,
             reference,
+            current_revision,
+            &url,
             files,
             topic,
             (parent_hash, parent_subject),
@@ -257,6 +376,8 @@ mod tests {
                 "Administrator",
                 "main",
                 "refs/changes/41/41/1",
+                "4efee3bab6f86af26cec8e3f798e9f6cfc0a41b7",
+                "http://pi:8000/c/dummy/+/41",
                 vec![],
                 None,
                 ("", ""),
@@ -271,10 +392,32 @@ mod tests {
                 "Administrator",
                 "main",
                 "refs/changes/02/2/2",
+                "6717c956c4c93cc3002f1310fd466c3bbe2e9897",
+                "http://pi:8000/c/dummy/+/2",
                 vec!["A README-md +1 -0".to_string()],
                 None,
                 ("", ""),
             )
         );
     }
+
+    #[test]
+    fn test_current_revision() {
+        let data = json::parse(
+            r#"{"current_revision": "bbb", "revisions": {"aaa": {"_number": 1}, "bbb": {"_number": 2}}}"#,
+        )
+        .unwrap();
+        assert_eq!(CommitInfo::get_current_revision(&data), "bbb");
+        let data = json::parse(
+            r#"{"current_revision_number": 2, "revisions": {"aaa": {"_number": 1}, "bbb": {"_number": 2}}}"#,
+        )
+        .unwrap();
+        assert_eq!(CommitInfo::get_current_revision(&data), "bbb");
+        let data = json::parse(r#"{"revisions": {"aaa": {"_number": 1}}}"#).unwrap();
+        assert_eq!(CommitInfo::get_current_revision(&data), "aaa");
+        assert_eq!(
+            CommitInfo::get_current_revision(&json::parse("{}").unwrap()),
+            ""
+        );
+    }
 }
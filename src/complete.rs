@@ -0,0 +1,128 @@
+use crate::remote::{self, RemoteUrl};
+use crate::repo_info::RepoInfo;
+use crate::REPO_INFO;
+
+// How long the owners, branches and topics are cached, in seconds
+const CACHE_TIME: u64 = 60 * 60;
+
+const OPERATORS: &[&str] = &[
+    "owner:",
+    "reviewer:",
+    "branch:",
+    "topic:",
+    "status:",
+    "is:",
+    "project:",
+    "change:",
+    "message:",
+    "file:",
+    "label:",
+    "age:",
+    "after:",
+    "before:",
+    "limit:",
+];
+
+const STATUSES: &[&str] = &["open", "merged", "abandoned", "closed"];
+
+const IS: &[&str] = &[
+    "open",
+    "closed",
+    "merged",
+    "abandoned",
+    "wip",
+    "private",
+    "reviewed",
+    "starred",
+    "watched",
+    "owner",
+    "reviewer",
+    "submittable",
+];
+
+// The owners, branches and topics of the open changes as query terms like owner:name
+fn fetch_terms() -> Vec<String> {
+    let mut query = "status:open limit:500".to_string();
+    if !REPO_INFO.project_name.is_empty() {
+        query += &format!(" project:{}", REPO_INFO.project_name);
+    }
+    let mut terms: Vec<String> = Vec::new();
+    for change in REPO_INFO.remote_url.list_changes(&query) {
+        let owner = &change["owner"];
+        if let Some(o) = owner["username"].as_str().or(owner["email"].as_str()) {
+            terms.push(format!("owner:{}", o));
+        }
+        if let Some(b) = change["branch"].as_str() {
+            terms.push(format!("branch:{}", b));
+        }
+        if let Some(t) = change["topic"].as_str() {
+            terms.push(format!("topic:{}", t));
+        }
+    }
+    terms.sort();
+    terms.dedup();
+    terms
+}
+
+fn cached_terms() -> Vec<String> {
+    let cache_file = REPO_INFO.get_completion_cache_file();
+    let key = format!(
+        "{} {}",
+        REPO_INFO.remote_url.base_url(),
+        REPO_INFO.project_name
+    );
+    let cache = std::fs::read_to_string(&cache_file).unwrap_or_default();
+    let mut lines = cache.lines();
+    if let (Some(k), Some(time)) = (lines.next(), lines.next()) {
+        let time: u64 = time.parse().unwrap_or(0);
+        if k == key && remote::now().saturating_sub(time) < CACHE_TIME {
+            return lines.map(|l| l.to_string()).collect();
+        }
+    }
+    let terms = fetch_terms();
+    let _ = std::fs::write(
+        cache_file,
+        format!("{}\n{}\n{}\n", key, remote::now(), terms.join("\n")),
+    );
+    terms
+}
+
+// Values for an operator, reviewers are completed from the owners
+fn values(operator: &str) -> Vec<String> {
+    let source = match operator {
+        "status" => return STATUSES.iter().map(|s| s.to_string()).collect(),
+        "is" => return IS.iter().map(|s| s.to_string()).collect(),
+        "owner" | "reviewer" => "owner:",
+        "branch" => "branch:",
+        "topic" => "topic:",
+        _ => return Vec::new(),
+    };
+    cached_terms()
+        .iter()
+        .filter_map(|t| t.strip_prefix(source))
+        .map(|v| v.to_string())
+        .collect()
+}
+
+// Prints the query terms starting with word, one per line, for the completion scripts
+pub fn run(word: &str) {
+    // Completion must stay quiet, so give up early where grt would fail
+    match RepoInfo::detect_repo_type() {
+        Some(t) if RemoteUrl::parse(&RepoInfo::guess_remote(&t)).is_some() => {}
+        _ => return,
+    }
+    let (negation, word) = match word.strip_prefix('-') {
+        Some(w) => ("-", w),
+        None => ("", word),
+    };
+    let candidates: Vec<String> = match word.split_once(':') {
+        Some((operator, _)) => values(operator)
+            .iter()
+            .map(|v| format!("{}:{}", operator, v))
+            .collect(),
+        None => OPERATORS.iter().map(|o| o.to_string()).collect(),
+    };
+    for c in candidates.iter().filter(|c| c.starts_with(word)) {
+        println!("{}{}", negation, c);
+    }
+}
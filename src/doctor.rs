@@ -0,0 +1,156 @@
+use std::process::Command;
+
+use crate::log;
+use crate::remote::RemoteUrl;
+use crate::repo_info::{RepoInfo, RepoType};
+use crate::REPO_INFO;
+
+fn report(label: &str, value: &str) {
+    println!("{:<17}{}", format!("{}:", label), value);
+}
+
+fn hint(text: &str) {
+    println!("{:<17}hint: {}", "", text);
+}
+
+// Explains why a request failed from the exit code of curl or ssh
+fn failure_hint(remote_type: &str, code: Option<i32>) {
+    match (remote_type, code) {
+        (_, None) => hint("curl or ssh could not be run"),
+        ("ssh", Some(255)) => hint("ssh could not connect or log in, check the host, port and user in the url and that your ssh key is added to Gerrit"),
+        ("ssh", _) => hint("The server refused the command, check that the user in the url has access to Gerrit"),
+        (_, Some(6)) => hint("Could not resolve the host, check the url and your network connection"),
+        (_, Some(7 | 28)) => hint("Could not connect to the server, check that it's reachable, e.g. that you're on the right network"),
+        (_, Some(35 | 51 | 58 | 60)) => hint("The TLS connection failed, check the server certificate"),
+        (_, Some(22)) if REPO_INFO.remote_url.is_authenticated() => hint("The server refused the request, check that ~/.netrc contains the right credentials (an HTTP password from the Gerrit settings) for the server"),
+        (_, Some(22)) => hint("The server returned an error, check that the url points to Gerrit"),
+        (_, Some(c)) => hint(&format!("curl failed with exit code {}", c)),
+    }
+}
+
+fn is_installed(tool: &str) -> bool {
+    let version_arg = if tool == "ssh" { "-V" } else { "--version" };
+    Command::new(tool).arg(version_arg).output().is_ok()
+}
+
+pub fn run() {
+    let tools: Vec<(&str, bool)> = ["git", "ssh", "curl", "repo"]
+        .iter()
+        .map(|t| (*t, is_installed(t)))
+        .collect();
+    for (tool, installed) in &tools {
+        report(tool, if *installed { "found" } else { "not found" });
+    }
+    let installed = |tool: &str| tools.iter().any(|(t, i)| *t == tool && *i);
+    if !installed("git") {
+        hint("grt needs git, install it and make sure it's in PATH");
+        return;
+    }
+    println!();
+
+    let repo_type = match RepoInfo::detect_repo_type() {
+        Some(t) => t,
+        None => {
+            report("Repository type", "none");
+            hint("Run grt inside a git repository or a repo workspace");
+            return;
+        }
+    };
+    report(
+        "Repository type",
+        match repo_type {
+            RepoType::Git => "git",
+            RepoType::Repo => "repo",
+        },
+    );
+    if repo_type == RepoType::Repo && !installed("repo") {
+        hint("repo is only needed for projects that aren't in the manifest");
+    }
+
+    let url = RepoInfo::guess_remote(&repo_type);
+    let remote_type = match RemoteUrl::parse(&url) {
+        Some(RemoteUrl::SSH(_)) => "ssh",
+        Some(RemoteUrl::HTTP(_)) => "http",
+        None => {
+            report(
+                "Gerrit server",
+                &format!("invalid url '{}'", log::redact(&url)),
+            );
+            hint("Set remote.origin.url (of the manifest repository for repo) to the ssh or http url of the Gerrit server");
+            return;
+        }
+    };
+    report("Remote type", remote_type);
+    report("Gerrit server", &log::redact(&url));
+    let tool = if remote_type == "ssh" { "ssh" } else { "curl" };
+    if !installed(tool) {
+        hint(&format!(
+            "{} is needed to talk to the server, install it",
+            tool
+        ));
+        return;
+    }
+
+    // Keep going if this fails, the other checks can tell more about why
+    let reachable = match REPO_INFO.remote_url.probe_version() {
+        Ok(Some(version)) => {
+            report("Gerrit version", &version);
+            true
+        }
+        Ok(None) => {
+            report(
+                "Gerrit version",
+                "unknown, the server didn't return a version",
+            );
+            true
+        }
+        Err(code) => {
+            report("Gerrit version", "unknown, the request failed");
+            failure_hint(remote_type, code);
+            false
+        }
+    };
+
+    if REPO_INFO.project_name.is_empty() {
+        report("Project", "none, all projects are searched");
+    } else {
+        report("Project", &REPO_INFO.project_name);
+    }
+
+    if !REPO_INFO.remote_url.is_authenticated() {
+        report("Authentication", "anonymous");
+        hint("Only public changes are shown, use an url ending in /a/ and put your credentials in ~/.netrc to log in");
+    } else {
+        match REPO_INFO.remote_url.get_account() {
+            Ok(Some(user)) => report("Authentication", &format!("logged in as {}", user)),
+            Ok(None) => report("Authentication", "logged in"),
+            Err(code) => {
+                report("Authentication", "failed");
+                // The version check already explained the failure
+                if reachable {
+                    failure_hint(remote_type, code);
+                }
+            }
+        }
+    }
+
+    let mut query = "limit:200 status:open".to_string();
+    if !REPO_INFO.project_name.is_empty() {
+        query += &format!(" project:{}", REPO_INFO.project_name);
+    }
+    let result = REPO_INFO.remote_url.perform_query(&query);
+    if !result.is_array() {
+        report("Sample query", &format!("'{}' failed", query));
+        hint("Run with --debug to see the query that is sent");
+    } else if result.is_empty() {
+        report("Sample query", &format!("'{}' found no changes", query));
+        if !REPO_INFO.project_name.is_empty() {
+            hint("Check that the project name matches the one in Gerrit, it's taken from remote.origin.projectname or the remote url");
+        }
+    } else {
+        report(
+            "Sample query",
+            &format!("'{}' found {} change(s)", query, result.len()),
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::process::Command;
+
+use crate::remote::now;
+use crate::{confirm, execute_commands, quote, show_commands, Commit, REPO_INFO, SETTINGS};
+
+const MAX_ENTRIES: usize = 20;
+
+struct Head {
+    path: String,
+    hash: String,
+    branch: String,
+}
+
+struct Entry {
+    time: u64,
+    method: String,
+    titles: Vec<String>,
+    heads: Vec<Head>,
+}
+
+impl Head {
+    fn new(path: &str) -> Option<Self> {
+        let path = std::fs::canonicalize(path).ok()?.to_str()?.to_string();
+        let hash = git_output(&path, &["rev-parse", "HEAD"]);
+        if hash.is_empty() {
+            return None;
+        }
+        let branch = git_output(&path, &["symbolic-ref", "--short", "-q", "HEAD"]);
+        Some(Head { path, hash, branch })
+    }
+
+    fn restore_command(&self) -> String {
+        let path = quote(&self.path);
+        if self.branch.is_empty() {
+            format!("git -C {} checkout --detach {}", path, self.hash)
+        } else {
+            format!(
+                "git -C {} checkout {} && git -C {} reset --keep {}",
+                path,
+                quote(&self.branch),
+                path,
+                self.hash
+            )
+        }
+    }
+}
+
+impl Entry {
+    fn to_lines(&self) -> String {
+        let mut s = format!(
+            "{}\t{}\t{}\n",
+            self.time,
+            self.method,
+            self.titles.join("\t")
+        );
+        for h in &self.heads {
+            s += &format!("\t{}\t{}\t{}\n", h.path, h.hash, h.branch);
+        }
+        s
+    }
+
+    fn age(&self) -> String {
+        let secs = now().saturating_sub(self.time);
+        match secs {
+            0..=59 => format!("{}s ago", secs),
+            60..=3599 => format!("{}m ago", secs / 60),
+            3600..=86399 => format!("{}h ago", secs / 3600),
+            _ => format!("{}d ago", secs / 86400),
+        }
+    }
+}
+
+fn git_output(path: &str, args: &[&str]) -> String {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .expect("Failed to run 'git'");
+    std::str::from_utf8(&out.stdout).unwrap().trim().to_string()
+}
+
+fn read() -> Vec<Entry> {
+    parse(&std::fs::read_to_string(REPO_INFO.get_history_file()).unwrap_or_default())
+}
+
+fn parse(content: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for line in content.lines() {
+        if let Some(head) = line.strip_prefix('\t') {
+            let parts: Vec<&str> = head.split('\t').collect();
+            if let (Some(e), [path, hash, branch]) = (entries.last_mut(), parts.as_slice()) {
+                e.heads.push(Head {
+                    path: path.to_string(),
+                    hash: hash.to_string(),
+                    branch: branch.to_string(),
+                });
+            }
+            continue;
+        }
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() >= 2 {
+            entries.push(Entry {
+                time: parts[0].parse().unwrap_or(0),
+                method: parts[1].to_string(),
+                titles: parts[2..].iter().map(|t| t.to_string()).collect(),
+                heads: Vec::new(),
+            });
+        }
+    }
+    entries
+}
+
+fn write(entries: &[Entry]) {
+    let skip = entries.len().saturating_sub(MAX_ENTRIES);
+    let content: String = entries.iter().skip(skip).map(|e| e.to_lines()).collect();
+    if let Err(e) = std::fs::write(REPO_INFO.get_history_file(), content) {
+        println!("Failed to write history: {}", e);
+    }
+}
+
+pub fn record(method: &str, titles: &[String], paths: &[String]) {
+    let heads: Vec<Head> = paths.iter().filter_map(|p| Head::new(p)).collect();
+    if heads.is_empty() {
+        return;
+    }
+    let mut entries = read();
+    entries.push(Entry {
+        time: now(),
+        method: method.to_string(),
+        titles: titles.to_vec(),
+        heads,
+    });
+    write(&entries);
+}
+
+pub fn list() {
+    let entries = read();
+    if entries.is_empty() {
+        println!("No operations recorded");
+        return;
+    }
+    for (i, e) in entries.iter().rev().enumerate() {
+        println!("{}: {} ({})", i + 1, e.method, e.age());
+        for t in &e.titles {
+            println!("    {}", t);
+        }
+    }
+}
+
+pub fn undo() {
+    let mut entries = read();
+    let entry = match entries.pop() {
+        Some(e) => e,
+        None => {
+            println!("Nothing to undo");
+            std::process::exit(1);
+        }
+    };
+    let mut commands: Vec<Commit> = entry
+        .heads
+        .iter()
+        .map(|h| {
+            let title = if h.branch.is_empty() {
+                format!("Restore {} to {}", h.path, &h.hash[..h.hash.len().min(12)])
+            } else {
+                format!("Restore {} to {}", h.path, h.branch)
+            };
+            Commit::new(title, h.restore_command(), &h.path)
+        })
+        .collect();
+    commands.reverse();
+
+    if SETTINGS.dry_run {
+        show_commands(&commands);
+        return;
+    }
+    println!(
+        "Undo {} ({}) of the following commit(s)?",
+        entry.method,
+        entry.age()
+    );
+    for t in &entry.titles {
+        println!("* {}", t);
+    }
+    if confirm("") {
+        println!();
+        if !execute_commands(commands) {
+            std::process::exit(1);
+        }
+        write(&entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let entry = Entry {
+            time: 1700000000,
+            method: "Cherry-Pick".to_string(),
+            titles: vec![
+                "First - Admin".to_string(),
+                "Second - Admin [topic]".to_string(),
+            ],
+            heads: vec![
+                Head {
+                    path: "/top/build".to_string(),
+                    hash: "6717c956c4c93cc3002f1310fd466c3bbe2e9897".to_string(),
+                    branch: "main".to_string(),
+                },
+                Head {
+                    path: "/top/tools".to_string(),
+                    hash: "4efee3bab6f86af26cec8e3f798e9f6cfc0a41b7".to_string(),
+                    branch: "".to_string(),
+                },
+            ],
+        };
+        let content = entry.to_lines() + &entry.to_lines();
+        let entries = parse(&content);
+        assert_eq!(entries.len(), 2);
+        let e = &entries[1];
+        assert_eq!(e.time, entry.time);
+        assert_eq!(e.method, entry.method);
+        assert_eq!(e.titles, entry.titles);
+        assert_eq!(e.heads.len(), 2);
+        assert_eq!(e.heads[0].path, "/top/build");
+        assert_eq!(e.heads[0].hash, "6717c956c4c93cc3002f1310fd466c3bbe2e9897");
+        assert_eq!(e.heads[0].branch, "main");
+        assert_eq!(e.heads[1].branch, "");
+        assert_eq!(e.to_lines(), entry.to_lines());
+    }
+}
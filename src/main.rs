@@ -1,4 +1,12 @@
+mod browser;
+mod cli;
 mod commit_info;
+mod complete;
+mod doctor;
+mod history;
+mod log;
+mod manifest;
+mod picker;
 mod remote;
 mod repo_info;
 mod settings;
@@ -9,140 +17,648 @@ use repo_info::RepoInfo;
 use settings::Settings;
 use skim::prelude::*;
 use std::collections::HashSet;
-use std::io::Write;
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Instant;
 
 use crate::repo_info::RepoType;
 
+// Number of output lines shown when a command fails
+const MAX_OUTPUT_LINES: usize = 10;
+
 lazy_static! {
     static ref SETTINGS: Settings = Settings::new();
     static ref REPO_INFO: RepoInfo = RepoInfo::new();
+    // Opened once by execute_commands, commands running in parallel share it
+    static ref LOG: Mutex<Option<std::fs::File>> = Mutex::new(None);
 }
 
 struct Commit {
     title: String,
     command: String,
+    project: String,
 }
 
 impl Commit {
-    fn new(title: String, command: String) -> Commit {
-        Commit { title, command }
+    fn new(title: String, command: String, project: &str) -> Commit {
+        Commit {
+            title,
+            command,
+            project: project.to_string(),
+        }
     }
-    fn execute(&self) -> bool {
-        let out = Command::new("sh")
+    // Runs the command and returns whether it succeeded and its output, the output is printed
+    // while running if stream is set
+    fn run(&self, stream: bool) -> (bool, Vec<String>) {
+        log::info(&log::redact(&format!("Running '{}'", self.command)));
+        let start = Instant::now();
+        let mut child = Command::new("sh")
             .arg("-c")
             .arg(&self.command)
-            .output()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
             .expect("Failed to run");
-        if out.status.success() {
-            println!("{}: Ok", self.title);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let readers = [
+            collect_output(child.stdout.take().unwrap(), output.clone(), stream),
+            collect_output(child.stderr.take().unwrap(), output.clone(), stream),
+        ];
+        let status = child.wait().expect("Failed to run");
+        for r in readers {
+            let _ = r.join();
+        }
+        let output = output.lock().unwrap().clone();
+        log::info(&format!(
+            "'{}' finished with {} in {}",
+            self.title,
+            status,
+            log::format_duration(start.elapsed())
+        ));
+        write_log(&self.command, &output, status.code());
+        (status.success(), output)
+    }
+
+    fn execute(&self) -> bool {
+        if SETTINGS.verbose {
+            println!("{}:", self.title);
         } else {
-            println!("{}: Failed", self.title);
-            //println!("{}", std::str::from_utf8(&out.stderr).unwrap());
-            //println!("{}", std::str::from_utf8(&out.stdout).unwrap());
+            print!("{}: ", self.title);
+            std::io::stdout().flush().unwrap();
+        }
+        let (ok, output) = self.run(SETTINGS.verbose);
+        if SETTINGS.verbose {
+            print!("{}: ", self.title);
+        }
+        if ok {
+            println!("Ok");
+        } else {
+            println!("Failed");
+            if !SETTINGS.verbose {
+                print!("{}", format_output(&output, false));
+            }
         }
-        return out.status.success();
+        ok
+    }
+}
+
+// Reads the output of a command line by line, printing it directly if stream is set
+fn collect_output(
+    stream: impl Read + Send + 'static,
+    output: Arc<Mutex<Vec<String>>>,
+    print: bool,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).split(b'\n').map_while(Result::ok) {
+            let line = String::from_utf8_lossy(&line);
+            // Progress output rewrites the line with \r, only keep the last update
+            let line = line.trim_end_matches('\r');
+            let line = line.rsplit('\r').next().unwrap_or("").to_string();
+            if print {
+                println!("    {}", line);
+            }
+            output.lock().unwrap().push(line);
+        }
+    })
+}
+
+// Formats the output of a failed command, only the last lines unless full is set
+fn format_output(output: &[String], full: bool) -> String {
+    let skip = if full {
+        0
+    } else {
+        output.len().saturating_sub(MAX_OUTPUT_LINES)
+    };
+    let mut text = String::new();
+    if skip > 0 {
+        text += &format!("    ... {} more line(s), use --verbose to see all\n", skip);
+    }
+    for line in &output[skip..] {
+        text += &format!("    {}\n", line);
     }
+    text
 }
 
-fn execute_command(selected_items: &Vec<Arc<dyn SkimItem>>) {
+fn write_log(command: &str, output: &[String], code: Option<i32>) {
+    let status = code.map_or("killed".to_string(), |c| c.to_string());
+    // Written at once so that entries from parallel commands don't get mixed up
+    let entry = format!(
+        "$ {}\n{}\n[exit status: {}]\n\n",
+        command,
+        output.join("\n"),
+        status
+    );
+    if let Some(log) = LOG.lock().unwrap().as_mut() {
+        let _ = log.write_all(entry.as_bytes());
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    let prompt = format!("{} (y/N) ", question);
+    if SETTINGS.yes {
+        println!("{}y", prompt.trim_start());
+        return true;
+    }
+    print!("{}", prompt.trim_start());
+    std::io::stdout().flush().unwrap();
     let mut line = String::new();
-    let mut topics: Vec<&str> = Vec::new();
-    let mut refs: HashSet<(String, String)> = HashSet::new();
-    for item in selected_items {
-        let commit = (**item)
-            .as_any()
-            .downcast_ref::<CommitInfo>()
-            .expect("Could not cast to CommitInfo");
-        if let Some(t) = &commit.topic {
-            topics.push(t);
+    std::io::stdin()
+        .read_line(&mut line)
+        .expect("Could not read user input");
+    ["y", "yes"].contains(&line.trim().to_lowercase().as_str())
+}
+
+fn print_commands(commands: &[Commit]) {
+    println!("The following commands would be run:");
+    for c in commands.iter().rev() {
+        println!("# {}", c.title);
+        println!("{}", c.command);
+    }
+}
+
+fn write_script(file: &str, commands: &[Commit]) {
+    let mut script = "#!/bin/sh\nset -e\n".to_string();
+    for c in commands.iter().rev() {
+        script += &format!("\n# {}\n{}\n", c.title, c.command);
+    }
+    std::fs::write(file, script).expect("Failed to write script");
+    println!("Wrote commands to {}", file);
+}
+
+// Quotes a value for use in the commands, which are run with sh -c
+fn quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:@%+=,".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+// Returns the git command and remote to download the commit with, None if the project isn't
+// in the manifest and the repo tool has to be used
+fn git_command(commit: &CommitInfo) -> Option<(String, String)> {
+    if REPO_INFO.repo_type == RepoType::Git {
+        return Some(("git".to_string(), "origin".to_string()));
+    }
+    let project = REPO_INFO.manifest.get_project(commit.get_project())?;
+    if project.remote.is_empty() {
+        return None;
+    }
+    Some((
+        format!("git -C {}", quote(&project.path)),
+        quote(&project.remote),
+    ))
+}
+
+fn checkout_command(commit: &CommitInfo) -> String {
+    let new_branch = SETTINGS.new_branch("Checkout");
+    let branch = if new_branch {
+        format!("-B {} ", quote(&commit.get_branch_name()))
+    } else {
+        "".to_string()
+    };
+    let (git, remote) = match git_command(commit) {
+        Some(g) => g,
+        None => {
+            return format!(
+                "repo download {}{}",
+                commit.get_repo_reference(),
+                if new_branch {
+                    format!(
+                        " && repo forall {} -c {}",
+                        quote(commit.get_project()),
+                        quote(&format!("git checkout {}HEAD", branch))
+                    )
+                } else {
+                    "".to_string()
+                }
+            )
+        }
+    };
+    if !SETTINGS.worktree("Checkout") {
+        return format!(
+            "{} fetch {} {} && {} checkout {}FETCH_HEAD",
+            git,
+            remote,
+            quote(&commit.get_git_reference()),
+            git,
+            branch
+        );
+    }
+    let path = quote(&commit.get_worktree_path());
+    if std::path::Path::new(&commit.get_worktree_path()).exists() {
+        format!(
+            "git -C {} fetch origin {} && git -C {} checkout {}FETCH_HEAD",
+            path,
+            quote(&commit.get_git_reference()),
+            path,
+            branch
+        )
+    } else {
+        format!(
+            "git fetch origin {} && git worktree add {}{} FETCH_HEAD",
+            quote(&commit.get_git_reference()),
+            if new_branch {
+                branch
+            } else {
+                "--detach ".to_string()
+            },
+            path
+        )
+    }
+}
+
+fn patch_dir(commit: &CommitInfo) -> String {
+    if REPO_INFO.repo_type == RepoType::Git {
+        return SETTINGS.patch_dir.clone();
+    }
+    // repo forall runs the command inside the project so the path must be absolute
+    std::env::current_dir()
+        .expect("Failed to get current directory")
+        .join(&SETTINGS.patch_dir)
+        .join(commit.get_project())
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+// patch_number is the position of the commit among the selected commits in the same project
+fn download_command(commit: &CommitInfo, patch_number: usize, method: &str) -> String {
+    let reference = quote(&commit.get_git_reference());
+    let (refspec, action) = match method {
+        "Checkout" => return checkout_command(commit),
+        "Cherry-Pick" if git_command(commit).is_none() => {
+            return format!(
+                "repo download {} --cherry-pick",
+                commit.get_repo_reference()
+            )
+        }
+        "Fetch" => (
+            quote(&format!(
+                "+{}:refs/heads/{}",
+                commit.get_git_reference(),
+                commit.get_branch_name()
+            )),
+            None,
+        ),
+        "Merge" => (reference, Some("merge --no-ff FETCH_HEAD".to_string())),
+        "Patch" => (
+            reference,
+            Some(format!(
+                "format-patch -1 FETCH_HEAD --start-number {} -o {}",
+                patch_number,
+                quote(&patch_dir(commit))
+            )),
+        ),
+        method => (
+            reference,
+            Some(format!("{} FETCH_HEAD", method.to_lowercase())),
+        ),
+    };
+    let (git, remote) =
+        git_command(commit).unwrap_or_else(|| ("git".to_string(), "$REPO_REMOTE".to_string()));
+    let fetch = format!("{} fetch {} {}", git, remote, refspec);
+    let command = match action {
+        Some(a) => format!("{} && {} {}", fetch, git, a),
+        None => fetch,
+    };
+    if git_command(commit).is_some() {
+        command
+    } else {
+        format!(
+            "repo forall {} -c {}",
+            quote(commit.get_project()),
+            quote(&command)
+        )
+    }
+}
+
+// Order the commits so that parents are downloaded before their children
+fn sort_by_parent(commits: &mut Vec<CommitInfo>) {
+    let mut sorted: Vec<CommitInfo> = Vec::new();
+    while !commits.is_empty() {
+        let i = commits
+            .iter()
+            .position(|c| {
+                !commits.iter().any(|o| {
+                    !o.get_revision().is_empty() && o.get_revision() == c.get_parent_revision()
+                })
+            })
+            .unwrap_or(0);
+        sorted.push(commits.remove(i));
+    }
+    *commits = sorted;
+}
+
+// Only keep the commits that aren't the parent of another selected commit, there should only be
+// one left per project unless unrelated commits were selected.
+fn stack_tips(commits: &mut Vec<CommitInfo>) {
+    let parents: Vec<String> = commits
+        .iter()
+        .map(|c| c.get_parent_revision().to_string())
+        .collect();
+    commits.retain(|c| !parents.iter().any(|p| p == c.get_revision()));
+
+    let mut projects: Vec<&str> = Vec::new();
+    for c in commits.iter() {
+        if projects.contains(&c.get_project()) {
+            println!(
+                "Can only rebase onto one commit per project, select a single stack of commits"
+            );
+            std::process::exit(1);
         }
+        projects.push(c.get_project());
+    }
+}
+
+fn is_dirty(path: &str) -> bool {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("status")
+        .arg("--porcelain")
+        .arg("--untracked-files=no")
+        .output()
+        .expect("Failed to run 'git'");
+    !out.stdout.is_empty()
+}
+
+// Returns the commands needed to stash and restore uncommitted changes and removes the commits
+// belonging to dirty projects that can't be stashed.
+fn check_dirty(commits: &mut Vec<CommitInfo>, method: &str) -> (Vec<Commit>, Vec<Commit>) {
+    let mut stash: Vec<Commit> = Vec::new();
+    let mut unstash: Vec<Commit> = Vec::new();
+    if SETTINGS.allow_dirty || !SETTINGS.modifies_worktree(method) {
+        return (stash, unstash);
+    }
+
+    let mut projects: Vec<&str> = Vec::new();
+    for c in commits.iter() {
+        if !projects.contains(&c.get_project()) {
+            projects.push(c.get_project());
+        }
+    }
+    let mut skipped: Vec<String> = Vec::new();
+    for project in projects {
+        let path = REPO_INFO.get_project_path(project);
+        if !is_dirty(&path) {
+            continue;
+        }
+        if SETTINGS.stash {
+            stash.push(Commit::new(
+                format!("Stash changes in {}", path),
+                format!("git -C {} stash push -m grt", quote(&path)),
+                project,
+            ));
+            unstash.insert(
+                0,
+                Commit::new(
+                    format!("Restore stashed changes in {}", path),
+                    format!("git -C {} stash pop", quote(&path)),
+                    project,
+                ),
+            );
+        } else {
+            skipped.push(project.to_string());
+        }
+    }
+
+    if !skipped.is_empty() {
         if REPO_INFO.repo_type == RepoType::Git {
-            refs.insert((commit.get_title(), commit.get_git_reference()));
+            println!("The working tree has uncommitted changes.");
         } else {
-            refs.insert((commit.get_title(), commit.get_repo_reference()));
-        }
-    }
-    if !topics.is_empty() {
-        println!("Your selected commits are part of the following topic(s):");
-        for t in &topics {
-            println!("* {}", t);
-        }
-        println!("Would you like to download those commits as well? (y/N)");
-        std::io::stdin()
-            .read_line(&mut line)
-            .expect("Could not read user input");
-        if ["y", "yes"].contains(&line.trim().to_lowercase().as_str()) {
-            for t in &topics {
-                let commits = REPO_INFO
-                    .remote_url
-                    .perform_query(&format!("status:open topic:{}", t));
-                for c in CommitInfo::parse_json(&commits) {
-                    refs.insert((c.get_title(), c.get_repo_reference()));
+            println!("The following project(s) have uncommitted changes and will be skipped:");
+            for p in &skipped {
+                println!("* {}", p);
+            }
+        }
+        println!("Use --stash to stash them or --allow-dirty to continue anyway.");
+        commits.retain(|c| !skipped.iter().any(|p| p == c.get_project()));
+        if commits.is_empty() {
+            std::process::exit(1);
+        }
+    }
+    (stash, unstash)
+}
+
+fn execute_command(selected_items: &Vec<Arc<dyn SkimItem>>, method: &str) {
+    let mut refs: HashSet<String> = HashSet::new();
+    let mut commits: Vec<CommitInfo> = Vec::new();
+    for item in selected_items {
+        let commit = CommitInfo::from_item(item);
+        if refs.insert(commit.get_git_reference()) {
+            commits.push(commit.clone());
+        }
+    }
+    if SETTINGS.with_topic {
+        let mut skipped: Vec<CommitInfo> = Vec::new();
+        for c in commits.clone() {
+            let (own, other) = c.get_topic_commits();
+            for t in own {
+                if refs.insert(t.get_git_reference()) {
+                    commits.push(t);
+                }
+            }
+            for t in other {
+                if refs.insert(t.get_git_reference()) {
+                    skipped.push(t);
                 }
             }
         }
-        line.clear();
+        CommitInfo::print_skipped(&skipped);
     }
-    println!("{} the following commit(s) now?", SETTINGS.method);
-    for (t, _) in &refs {
-        println!("* {}", t);
+
+    if method == "Rebase" {
+        stack_tips(&mut commits);
     }
-    print!("(y/N) ");
-    std::io::stdout().flush().unwrap();
+    sort_by_parent(&mut commits);
 
-    let mut commands: Vec<Commit> = if REPO_INFO.repo_type == RepoType::Git {
-        refs.iter()
-            .map(|(t, i)| {
-                Commit::new(
-                    t.to_string(),
-                    format!(
-                        "git fetch origin {} && git {} FETCH_HEAD",
-                        i,
-                        SETTINGS.method.to_lowercase()
-                    ),
-                )
-            })
-            .collect()
-    } else {
-        refs.iter()
-            .map(|(t, i)| {
-                Commit::new(
-                    t.to_string(),
-                    format!(
-                        "repo download {} {}",
-                        i,
-                        if SETTINGS.method.to_lowercase() == "cherry-pick" {
-                            "--cherry-pick"
-                        } else {
-                            ""
-                        }
-                    ),
-                )
-            })
-            .collect()
-    };
-    std::io::stdin()
-        .read_line(&mut line)
-        .expect("Could not read user input");
-    if ["y", "yes"].contains(&line.trim().to_lowercase().as_str()) {
+    let (stash, unstash) = check_dirty(&mut commits, method);
+    let mut commands: Vec<Commit> = stash
+        .into_iter()
+        .chain(commits.iter().enumerate().map(|(i, c)| {
+            let patch_number = commits[..i]
+                .iter()
+                .filter(|o| o.get_project() == c.get_project())
+                .count()
+                + 1;
+            Commit::new(
+                c.get_title(),
+                download_command(c, patch_number, method),
+                c.get_project(),
+            )
+        }))
+        .chain(unstash)
+        .collect();
+    // Commands are popped from the back when executed
+    commands.reverse();
+
+    if SETTINGS.dry_run {
+        show_commands(&commands);
+        return;
+    }
+
+    match method {
+        "Rebase" => println!("Rebase onto the following commit now?"),
+        "Patch" => println!("Create patches for the following commit(s) now?"),
+        method => println!("{} the following commit(s) now?", method),
+    }
+    for c in &commits {
+        println!("* {}", c.get_title());
+    }
+    if confirm("") {
         println!();
-        while commands.len() > 0 {
-            let c = commands.pop().unwrap();
-            if !c.execute() && commands.len() > 0 {
-                println!("Do you want to continue? (y/N)");
-                std::io::stdin()
-                    .read_line(&mut line)
-                    .expect("Could not read user input");
-                if !["y", "yes"].contains(&line.trim().to_lowercase().as_str()) {
-                    break;
+        if SETTINGS.modifies_worktree(method) {
+            let titles = commits
+                .iter()
+                .map(|c| c.get_title())
+                .collect::<Vec<String>>();
+            let mut paths: Vec<String> = Vec::new();
+            for c in &commits {
+                let path = REPO_INFO.get_project_path(c.get_project());
+                if !paths.contains(&path) {
+                    paths.push(path);
                 }
             }
+            history::record(method, &titles, &paths);
+        }
+        if !execute_commands(commands) {
+            std::process::exit(1);
         }
+    } else {
+        print_remaining(&commands);
+    }
+}
+
+fn show_commands(commands: &[Commit]) {
+    match &SETTINGS.script {
+        Some(file) => write_script(file, commands),
+        None => print_commands(commands),
     }
-    if commands.len() > 0 {
+}
+
+// Returns true if all commands were executed successfully
+fn execute_commands(commands: Vec<Commit>) -> bool {
+    let log_file = REPO_INFO.get_log_file();
+    *LOG.lock().unwrap() = std::fs::File::create(&log_file).ok();
+    let (results, commands) = if SETTINGS.jobs > 1 && REPO_INFO.repo_type == RepoType::Repo {
+        execute_parallel(commands)
+    } else {
+        execute_sequential(commands)
+    };
+    if REPO_INFO.repo_type == RepoType::Repo {
+        print_summary(&results, &commands);
+    }
+    print_remaining(&commands);
+    if results.iter().any(|(_, ok)| !*ok) {
+        println!("The output of all commands was written to {}", log_file);
+    }
+    results.iter().all(|(_, ok)| *ok) && commands.is_empty()
+}
+
+// Returns the project and result of each executed command and the commands that weren't run
+fn execute_sequential(mut commands: Vec<Commit>) -> (Vec<(String, bool)>, Vec<Commit>) {
+    let mut results: Vec<(String, bool)> = Vec::new();
+    while let Some(c) = commands.pop() {
+        let ok = c.execute();
+        results.push((c.project.clone(), ok));
+        if !ok && (commands.is_empty() || SETTINGS.yes || !confirm("Do you want to continue?")) {
+            break;
+        }
+    }
+    (results, commands)
+}
+
+// Runs the commands for different projects in parallel, the commands within a project are run
+// in order and stop at the first failure
+fn execute_parallel(commands: Vec<Commit>) -> (Vec<(String, bool)>, Vec<Commit>) {
+    let mut projects: Vec<Vec<Commit>> = Vec::new();
+    for c in commands.into_iter().rev() {
+        match projects.iter_mut().find(|p| p[0].project == c.project) {
+            Some(p) => p.push(c),
+            None => projects.push(vec![c]),
+        }
+    }
+    let order: Vec<String> = projects.iter().map(|p| p[0].project.clone()).collect();
+    let queue = Mutex::new(projects.into_iter());
+    let results: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
+    let remaining: Mutex<Vec<Commit>> = Mutex::new(Vec::new());
+    std::thread::scope(|s| {
+        for _ in 0..SETTINGS.jobs {
+            s.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let mut project = match next {
+                    Some(p) => p.into_iter(),
+                    None => break,
+                };
+                for c in project.by_ref() {
+                    let (ok, output) = c.run(false);
+                    if ok {
+                        println!("{}: Ok", c.title);
+                    } else {
+                        print!(
+                            "{}: Failed\n{}",
+                            c.title,
+                            format_output(&output, SETTINGS.verbose)
+                        );
+                    }
+                    results.lock().unwrap().push((c.project.clone(), ok));
+                    if !ok {
+                        break;
+                    }
+                }
+                remaining.lock().unwrap().extend(project);
+            });
+        }
+    });
+    let mut remaining = remaining.into_inner().unwrap();
+    // Commands are popped from the back when executed
+    remaining.reverse();
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(p, _)| order.iter().position(|o| o == p));
+    (results, remaining)
+}
+
+fn print_summary(results: &[(String, bool)], remaining: &[Commit]) {
+    let mut projects: Vec<&str> = Vec::new();
+    for p in results
+        .iter()
+        .map(|(p, _)| p.as_str())
+        .chain(remaining.iter().rev().map(|c| c.project.as_str()))
+    {
+        if !projects.contains(&p) {
+            projects.push(p);
+        }
+    }
+    if projects.len() < 2 {
+        return;
+    }
+    println!();
+    println!("Summary:");
+    for p in projects {
+        let ok = results.iter().filter(|(rp, ok)| rp == p && *ok).count();
+        let failed = results.iter().filter(|(rp, ok)| rp == p && !*ok).count();
+        let not_run = remaining.iter().filter(|c| c.project == p).count();
+        let mut line = format!("{}: {} ok", p, ok);
+        if failed > 0 {
+            line += &format!(", {} failed", failed);
+        }
+        if not_run > 0 {
+            line += &format!(", {} not run", not_run);
+        }
+        println!("* {}", line);
+    }
+}
+
+fn print_remaining(commands: &[Commit]) {
+    if !commands.is_empty() {
         let command = commands
             .iter()
+            .rev()
             .map(|c| c.command.clone())
             .collect::<Vec<String>>()
             .join(" && ");
@@ -151,33 +667,98 @@ fn execute_command(selected_items: &Vec<Arc<dyn SkimItem>>) {
 }
 
 fn main() {
-    let selector: Option<std::rc::Rc<(dyn skim::Selector + 'static)>> = if SETTINGS.select_all {
-        Some(Rc::new(DefaultSkimSelector::default().regex(".*")))
-    } else {
-        None
+    // Completion is handled before the settings are parsed, so that usage errors from bad
+    // defaults in GRT_ARGS can't end up as candidates
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("__complete") {
+        let word = args.iter().skip(2).find(|a| *a != "--");
+        return complete::run(word.map_or("", |a| a.as_str()));
+    }
+    match SETTINGS.method() {
+        "Undo" => return history::undo(),
+        "History" => return history::list(),
+        "Doctor" => return doctor::run(),
+        "Completions" => return cli::print_completions(SETTINGS.args.first().map(|a| a.as_str())),
+        _ => {}
+    }
+
+    let commit_info = match &SETTINGS.direct_change {
+        Some((change, patchset)) => REPO_INFO.remote_url.get_change(change, patchset.as_deref()),
+        None => REPO_INFO.remote_url.perform_query(&SETTINGS.query),
     };
-    let options = SkimOptionsBuilder::default()
-        .height(Some("50%"))
-        .multi(true)
-        .select1(true)
-        .exit0(true)
-        .selector(selector)
-        .preview(Some("")) // preview should be specified to enable preview window
-        .build()
-        .unwrap();
-
-    let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
-    let commit_info = REPO_INFO.remote_url.perform_query(&SETTINGS.query);
-    CommitInfo::parse_json(&commit_info)
-        .map(Arc::new)
-        .for_each(|x| {
-            let _ = tx_item.send(x);
-        });
-    drop(tx_item); // so that skim could know when to stop waiting for more items.
-
-    let res = &Skim::run_with(&options, Some(rx_item)).unwrap();
-    if res.final_event == Event::EvActAbort {
+    if SETTINGS.method() == "List" {
+        for c in CommitInfo::parse_json(&commit_info) {
+            println!("{}\t{}", c.get_number(), c.get_title());
+        }
+        return;
+    }
+    let items: Vec<Arc<dyn SkimItem>> = CommitInfo::parse_json(&commit_info)
+        .map(|c| Arc::new(c) as Arc<dyn SkimItem>)
+        .collect();
+
+    if let (Some((change, _)), true) = (&SETTINGS.direct_change, items.is_empty()) {
+        println!("Could not find change {}", change);
         std::process::exit(1);
     }
-    execute_command(&res.selected_items)
+    let (selected_items, action) = if SETTINGS.direct_change.is_some() && items.len() == 1 {
+        (items, None)
+    } else if SETTINGS.non_interactive() {
+        (picker::select(items), None)
+    } else {
+        picker::pick(items)
+    };
+    if SETTINGS.method() == "Open" && action.is_none() {
+        let commits: Vec<&CommitInfo> = selected_items.iter().map(CommitInfo::from_item).collect();
+        return browser::show(&commits);
+    }
+    let method = action.unwrap_or(SETTINGS.method());
+    if action.is_some() {
+        SETTINGS.warn_ignored(method);
+    }
+    execute_command(&selected_items, method)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(revision: &str, parent: &str) -> CommitInfo {
+        CommitInfo::new(
+            "dummy",
+            revision,
+            "",
+            "Administrator",
+            "main",
+            "refs/changes/01/1/1",
+            revision,
+            "",
+            vec![],
+            None,
+            (parent, ""),
+        )
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut commits = vec![
+            commit("ccc", "bbb"),
+            commit("aaa", "000"),
+            commit("bbb", "aaa"),
+        ];
+        sort_by_parent(&mut commits);
+        let revisions: Vec<&str> = commits.iter().map(|c| c.get_revision()).collect();
+        assert_eq!(revisions, ["aaa", "bbb", "ccc"]);
+        stack_tips(&mut commits);
+        let revisions: Vec<&str> = commits.iter().map(|c| c.get_revision()).collect();
+        assert_eq!(revisions, ["ccc"]);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("review/41-2"), "review/41-2");
+        assert_eq!(quote("my worktree"), "'my worktree'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("$(rm -rf)"), "'$(rm -rf)'");
+    }
 }
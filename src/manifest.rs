@@ -0,0 +1,267 @@
+use std::path::Path;
+
+pub struct Project {
+    pub name: String,
+    pub path: String,
+    pub remote: String,
+}
+
+#[derive(Default)]
+pub struct Manifest {
+    projects: Vec<Project>,
+}
+
+// A start or empty element tag with its attributes
+struct Tag {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    fn get(&self, attribute: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(a, _)| a == attribute)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Only the tags are needed from the manifest, text, comments and end tags are skipped
+fn parse_tags(content: &str) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.split_once("-->").map_or("", |(_, r)| r);
+            continue;
+        }
+        let end = rest.find('>').unwrap_or(rest.len());
+        let tag = rest[..end].trim_end_matches('/');
+        rest = &rest[(end + 1).min(rest.len())..];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let (name, mut attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let mut attributes: Vec<(String, String)> = Vec::new();
+        while let Some((attr, value)) = attrs.split_once('=') {
+            let value = value.trim_start();
+            let quote = match value.chars().next() {
+                Some(q) if q == '"' || q == '\'' => q,
+                _ => break,
+            };
+            let (value, r) = value[1..].split_once(quote).unwrap_or((&value[1..], ""));
+            attributes.push((attr.trim().to_string(), unescape(value)));
+            attrs = r;
+        }
+        tags.push(Tag {
+            name: name.to_string(),
+            attributes,
+        });
+    }
+    tags
+}
+
+impl Manifest {
+    // Reads the manifest of the repo workspace at toplevel, including local manifests
+    pub fn read(toplevel: &str) -> Self {
+        let repo_dir = Path::new(toplevel).join(".repo");
+        let mut tags: Vec<Tag> = Vec::new();
+        read_tags(&repo_dir.join("manifest.xml"), &repo_dir, &mut tags);
+        if let Ok(entries) = std::fs::read_dir(repo_dir.join("local_manifests")) {
+            let mut files: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            files.sort();
+            for f in files
+                .iter()
+                .filter(|f| f.extension().is_some_and(|e| e == "xml"))
+            {
+                read_tags(f, &repo_dir, &mut tags);
+            }
+        }
+        let mut manifest = Self::from_tags(&tags, toplevel);
+        // project.list contains the paths of the projects that have been synced
+        if let Ok(list) = std::fs::read_to_string(repo_dir.join("project.list")) {
+            let synced: Vec<String> = list
+                .lines()
+                .map(|l| Path::new(toplevel).join(l).to_str().unwrap().to_string())
+                .collect();
+            manifest.projects.retain(|p| synced.contains(&p.path));
+        }
+        manifest
+    }
+
+    fn from_tags(tags: &[Tag], toplevel: &str) -> Self {
+        let default_remote = tags
+            .iter()
+            .filter(|t| t.name == "default")
+            .filter_map(|t| t.get("remote"))
+            .next_back()
+            .unwrap_or("");
+        let full_path = |path: &str| Path::new(toplevel).join(path).to_str().unwrap().to_string();
+        let mut projects: Vec<Project> = Vec::new();
+        for t in tags {
+            let name = match t.get("name") {
+                Some(n) => n,
+                None => continue,
+            };
+            match t.name.as_str() {
+                "project" => {
+                    projects.retain(|p| p.name != name);
+                    projects.push(Project {
+                        name: name.to_string(),
+                        path: full_path(t.get("path").unwrap_or(name)),
+                        remote: t.get("remote").unwrap_or(default_remote).to_string(),
+                    });
+                }
+                // path only limits the change to the project checked out there
+                "extend-project" => {
+                    let path = t.get("path").map(full_path);
+                    for p in projects.iter_mut().filter(|p| {
+                        p.name == name && path.as_ref().is_none_or(|path| *path == p.path)
+                    }) {
+                        if let Some(dest) = t.get("dest-path") {
+                            p.path = full_path(dest);
+                        }
+                        if let Some(remote) = t.get("remote") {
+                            p.remote = remote.to_string();
+                        }
+                    }
+                }
+                "remove-project" => projects.retain(|p| p.name != name),
+                _ => {}
+            }
+        }
+        // repo names the git remote after the alias of the manifest remote if it has one
+        for p in &mut projects {
+            if let Some(alias) = tags
+                .iter()
+                .filter(|t| t.name == "remote" && t.get("name") == Some(&p.remote))
+                .filter_map(|t| t.get("alias"))
+                .next_back()
+            {
+                p.remote = alias.to_string();
+            }
+        }
+        Manifest { projects }
+    }
+
+    pub fn get_project(&self, name: &str) -> Option<&Project> {
+        self.projects.iter().find(|p| p.name == name)
+    }
+}
+
+// Includes are relative to the manifests directory
+fn read_tags(file: &Path, repo_dir: &Path, tags: &mut Vec<Tag>) {
+    let content = std::fs::read_to_string(file).unwrap_or_default();
+    for t in parse_tags(&content) {
+        if t.name == "include" {
+            if let Some(name) = t.get("name") {
+                read_tags(&repo_dir.join("manifests").join(name), repo_dir, tags);
+            }
+        } else {
+            tags.push(t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+  <!-- <project name="commented" /> -->
+  <remote name="origin" fetch=".." review="https://gerrit" />
+  <remote name="other" fetch="https://other" />
+  <default revision="main" remote="origin" sync-j="4" />
+  <project name="platform/build" path="build" />
+  <project name="tools" remote="other">
+    <linkfile src="a" dest="b" />
+  </project>
+  <project name="removed" />
+  <remove-project name="removed" />
+</manifest>"#;
+        let manifest = Manifest::from_tags(&parse_tags(content), "/top");
+        assert_eq!(manifest.projects.len(), 2);
+        let build = manifest.get_project("platform/build").unwrap();
+        assert_eq!(
+            (build.path.as_str(), build.remote.as_str()),
+            ("/top/build", "origin")
+        );
+        let tools = manifest.get_project("tools").unwrap();
+        assert_eq!(
+            (tools.path.as_str(), tools.remote.as_str()),
+            ("/top/tools", "other")
+        );
+        assert!(manifest.get_project("removed").is_none());
+    }
+
+    #[test]
+    fn test_read() {
+        let top = std::env::temp_dir().join(format!("grt-test-{}", std::process::id()));
+        let repo_dir = top.join(".repo");
+        std::fs::create_dir_all(repo_dir.join("manifests")).unwrap();
+        std::fs::create_dir_all(repo_dir.join("local_manifests")).unwrap();
+        std::fs::write(
+            repo_dir.join("manifest.xml"),
+            r#"<manifest><include name="default.xml" /></manifest>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            repo_dir.join("manifests/default.xml"),
+            r#"<manifest>
+  <remote name="origin" alias="gerrit" fetch=".." />
+  <default remote="origin" />
+  <project name="build" />
+  <project name="tools" />
+  <project name="docs" />
+  <project name="unsynced" />
+</manifest>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            repo_dir.join("local_manifests/local.xml"),
+            r#"<manifest>
+  <remote name="extra" fetch="https://extra" />
+  <project name="local" remote="extra" />
+  <extend-project name="tools" dest-path="moved/tools" remote="extra" />
+  <extend-project name="build" path="elsewhere" remote="extra" />
+  <remove-project name="docs" />
+</manifest>"#,
+        )
+        .unwrap();
+        std::fs::write(
+            repo_dir.join("project.list"),
+            "build\nmoved/tools\ndocs\nlocal\n",
+        )
+        .unwrap();
+        let toplevel = top.to_str().unwrap();
+        let manifest = Manifest::read(toplevel);
+        let _ = std::fs::remove_dir_all(&top);
+
+        assert_eq!(manifest.projects.len(), 3);
+        let build = manifest.get_project("build").unwrap();
+        assert_eq!(
+            (build.path.as_str(), build.remote.as_str()),
+            (format!("{}/build", toplevel).as_str(), "gerrit")
+        );
+        let tools = manifest.get_project("tools").unwrap();
+        assert_eq!(
+            (tools.path.as_str(), tools.remote.as_str()),
+            (format!("{}/moved/tools", toplevel).as_str(), "extra")
+        );
+        assert!(manifest.get_project("local").is_some());
+        assert!(manifest.get_project("docs").is_none());
+        assert!(manifest.get_project("unsynced").is_none());
+    }
+}
@@ -0,0 +1,447 @@
+use skim::prelude::*;
+use std::fs::DirBuilder;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::browser;
+use crate::commit_info::CommitInfo;
+use crate::settings::Settings;
+use crate::{quote, REPO_INFO, SETTINGS};
+
+// Move commits in the same topic next to the first commit of that topic
+fn group_by_topic(items: Vec<Arc<dyn SkimItem>>) -> Vec<Arc<dyn SkimItem>> {
+    let mut grouped: Vec<Arc<dyn SkimItem>> = Vec::new();
+    for item in items {
+        let topic = &CommitInfo::from_item(&item).topic;
+        let pos = match topic {
+            Some(_) => grouped
+                .iter()
+                .rposition(|i| &CommitInfo::from_item(i).topic == topic)
+                .map(|p| p + 1),
+            None => None,
+        };
+        match pos {
+            Some(p) => grouped.insert(p, item),
+            None => grouped.push(item),
+        }
+    }
+    grouped
+}
+
+// The commits skipped in other projects are collected to be printed once the picker is closed
+fn expand_topics(
+    items: &mut Vec<Arc<dyn SkimItem>>,
+    selected: &[Arc<dyn SkimItem>],
+    skipped: &mut Vec<CommitInfo>,
+) -> Vec<String> {
+    let mut preselect: Vec<String> = selected.iter().map(|i| i.text().to_string()).collect();
+    for item in selected {
+        let (own, other) = CommitInfo::from_item(item).get_topic_commits();
+        for c in own {
+            preselect.push(c.get_title());
+            if !items.iter().any(|i| i.output() == c.output()) {
+                items.push(Arc::new(c));
+            }
+        }
+        for c in other {
+            if !skipped.iter().any(|s| s.output() == c.output()) {
+                skipped.push(c);
+            }
+        }
+    }
+    preselect
+}
+
+// The result of showing the picker once, key is the expect key that closed it, if any
+struct Picked {
+    items: Vec<Arc<dyn SkimItem>>,
+    key: Option<String>,
+}
+
+fn skim_pick(
+    items: &[Arc<dyn SkimItem>],
+    preselect: &[String],
+    first_run: bool,
+    keys: &str,
+    header: &str,
+) -> Picked {
+    let selector: Option<Rc<dyn Selector>> = if !preselect.is_empty() {
+        Some(Rc::new(
+            DefaultSkimSelector::default().preset(preselect.to_vec()),
+        ))
+    } else if SETTINGS.select_all {
+        Some(Rc::new(DefaultSkimSelector::default().regex(".*")))
+    } else {
+        None
+    };
+    let options = SkimOptionsBuilder::default()
+        .height(Some(&SETTINGS.height))
+        .reverse(SETTINGS.reverse)
+        .color(SETTINGS.color.as_deref())
+        .prompt(Some(&SETTINGS.prompt))
+        .multi(SETTINGS.multi)
+        .select1(first_run)
+        .exit0(first_run)
+        .selector(selector)
+        .expect(Some(keys.to_string()))
+        .header(Some(header))
+        .preview(Some("")) // preview should be specified to enable preview window
+        .preview_window(Some(&SETTINGS.preview_window))
+        .build()
+        .unwrap();
+
+    let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
+    items.iter().for_each(|x| {
+        let _ = tx_item.send(x.clone());
+    });
+    drop(tx_item); // so that skim could know when to stop waiting for more items.
+
+    let res = Skim::run_with(&options, Some(rx_item)).unwrap();
+    if res.is_abort {
+        std::process::exit(1);
+    }
+    let key = match res.final_event {
+        Event::EvActAccept(key) => key,
+        _ => None,
+    };
+    Picked {
+        items: res.selected_items,
+        key,
+    }
+}
+
+// Runs fzf with the given arguments and input, returns the output lines or None if aborted
+fn run_fzf(args: &[String], input: &str) -> Option<Vec<String>> {
+    let child = Command::new("fzf")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(_) => {
+            println!("Failed to run 'fzf', is it installed?");
+            std::process::exit(1);
+        }
+    };
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let out = child.wait_with_output().expect("Failed to wait for 'fzf'");
+    // 1 means that nothing matched, 130 that the user aborted
+    if out.status.code() != Some(0) && out.status.code() != Some(1) {
+        return None;
+    }
+    Some(
+        std::str::from_utf8(&out.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect(),
+    )
+}
+
+fn fzf_args(prompt: &str) -> Vec<String> {
+    let mut args = vec![
+        format!("--height={}", SETTINGS.height),
+        format!("--prompt={}", prompt),
+    ];
+    if SETTINGS.reverse {
+        args.push("--layout=reverse".to_string());
+    }
+    if let Some(color) = &SETTINGS.color {
+        args.push(format!("--color={}", color));
+    }
+    args
+}
+
+// A new directory in the temp dir that only the user can access
+fn create_private_dir() -> Option<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    (0..10).find_map(|i| {
+        let dir = std::env::temp_dir().join(format!(
+            "grt-{}-{}",
+            std::process::id(),
+            nanos.wrapping_add(i)
+        ));
+        // Fails if the directory exists, so one created by someone else is never used
+        DirBuilder::new().mode(0o700).create(&dir).ok()?;
+        Some(dir)
+    })
+}
+
+fn fzf_pick(
+    items: &[Arc<dyn SkimItem>],
+    preselect: &[String],
+    first_run: bool,
+    keys: &str,
+    header: &str,
+) -> Picked {
+    // fzf can't call back into grt, so the previews are written to files up front. Looking up
+    // the parents would mean a query per commit before fzf starts, so they're left out.
+    let preview_dir = match create_private_dir() {
+        Some(d) => d,
+        None => {
+            println!("Failed to create a directory for the previews");
+            std::process::exit(1);
+        }
+    };
+    let mut input = String::new();
+    for (i, item) in items.iter().enumerate() {
+        let body = CommitInfo::from_item(item).get_body_with_parent(false);
+        let _ = std::fs::write(preview_dir.join(i.to_string()), body);
+        input += &format!("{}\t{}\n", i, item.text());
+    }
+
+    let mut args = fzf_args(&SETTINGS.prompt);
+    args.extend([
+        "--delimiter=\t".to_string(),
+        "--with-nth=2..".to_string(),
+        format!("--expect={}", keys),
+        format!("--header={}", header),
+        format!(
+            "--preview=cat {}/{{1}}",
+            quote(preview_dir.to_str().unwrap())
+        ),
+        format!("--preview-window={}", SETTINGS.preview_window),
+    ]);
+    if SETTINGS.multi {
+        args.push("--multi".to_string());
+    }
+    if SETTINGS.select_all {
+        args.push("--bind=load:select-all".to_string());
+    }
+    // fzf has no option to select given lines, so they're selected one by one once loaded
+    let select: Vec<String> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| preselect.iter().any(|p| *p == item.text()))
+        .map(|(i, _)| format!("pos({})+select", i + 1))
+        .collect();
+    if SETTINGS.multi && !select.is_empty() {
+        args.push(format!("--bind=load:{}+first", select.join("+")));
+    }
+    if first_run {
+        args.push("--select-1".to_string());
+        args.push("--exit-0".to_string());
+    }
+    let lines = run_fzf(&args, &input);
+    let _ = std::fs::remove_dir_all(&preview_dir);
+    let lines = match lines {
+        Some(l) => l,
+        None => std::process::exit(1),
+    };
+
+    // With --expect the first line is the key that was pressed, empty for enter
+    let mut lines = lines.into_iter();
+    let key = lines.next().filter(|k| !k.is_empty());
+    let items = lines
+        .filter_map(|l| l.split('\t').next()?.parse::<usize>().ok())
+        .filter_map(|i| items.get(i).cloned())
+        .collect();
+    Picked { items, key }
+}
+
+// Parses selections like "1 3-5" into indices, None if it's not valid
+fn parse_selection(input: &str, len: usize) -> Option<Vec<usize>> {
+    if input == "a" {
+        return Some((0..len).collect());
+    }
+    let mut selected = Vec::new();
+    for part in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        if part.is_empty() {
+            continue;
+        }
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+        if first == 0 || last > len || first > last {
+            return None;
+        }
+        selected.extend(first - 1..last);
+    }
+    Some(selected)
+}
+
+fn numbered_pick(items: &[Arc<dyn SkimItem>], first_run: bool) -> Picked {
+    if first_run && items.len() <= 1 {
+        return Picked {
+            items: items.to_vec(),
+            key: None,
+        };
+    }
+    for (i, item) in items.iter().enumerate() {
+        println!("{:3}) {}", i + 1, item.text());
+    }
+    loop {
+        let prompt = if SETTINGS.multi {
+            "Select commits (e.g. '1 3-5', 'a' for all, empty to abort): "
+        } else {
+            "Select a commit (empty to abort): "
+        };
+        let mut input = String::new();
+        print!("{}", prompt);
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stdin().read_line(&mut input);
+        let input = input.trim();
+        if input.is_empty() {
+            std::process::exit(1);
+        }
+        match parse_selection(input, items.len()) {
+            Some(s) if !s.is_empty() && (SETTINGS.multi || s.len() == 1) => {
+                return Picked {
+                    items: s.into_iter().map(|i| items[i].clone()).collect(),
+                    key: None,
+                };
+            }
+            _ => println!("Invalid selection '{}'", input),
+        }
+    }
+}
+
+// Let the user edit the Gerrit query in a second prompt, None if it was aborted
+fn edit_query(query: &str) -> Option<String> {
+    let header = "Edit the query and press enter to reload, esc to cancel";
+    if SETTINGS.picker == "fzf" {
+        let mut args = fzf_args("Query> ");
+        args.extend([
+            "--print-query".to_string(),
+            format!("--query={}", query),
+            format!("--header={}", header),
+        ]);
+        return run_fzf(&args, "")?
+            .into_iter()
+            .next()
+            .map(|q| q.trim().to_string());
+    }
+    let options = SkimOptionsBuilder::default()
+        .height(Some(&SETTINGS.height))
+        .reverse(SETTINGS.reverse)
+        .color(SETTINGS.color.as_deref())
+        .prompt(Some("Query> "))
+        .query(Some(query))
+        .header(Some(header))
+        .build()
+        .unwrap();
+    let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
+    drop(tx_item);
+    let res = Skim::run_with(&options, Some(rx_item))?;
+    if res.is_abort {
+        return None;
+    }
+    Some(res.query.trim().to_string())
+}
+
+fn load(query: &str) -> Vec<Arc<dyn SkimItem>> {
+    let commit_info = REPO_INFO.remote_url.perform_query(query);
+    let items = CommitInfo::parse_json(&commit_info)
+        .map(|c| Arc::new(c) as Arc<dyn SkimItem>)
+        .collect();
+    group_by_topic(items)
+}
+
+// Returns the selected items and the method of the action they were selected with, if any
+pub fn pick(items: Vec<Arc<dyn SkimItem>>) -> (Vec<Arc<dyn SkimItem>>, Option<&'static str>) {
+    let mut items = group_by_topic(items);
+    let mut query = SETTINGS.query.clone();
+    let mut preselect: Vec<String> = Vec::new();
+    let mut skipped: Vec<CommitInfo> = Vec::new();
+    let mut first_run = true;
+    let keys = SETTINGS
+        .bindings
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<&str>>()
+        .join(",");
+    let header = SETTINGS
+        .bindings
+        .iter()
+        .map(|(k, a)| format!("{}: {}", k, a))
+        .collect::<Vec<String>>()
+        .join(", ");
+    loop {
+        let res = match SETTINGS.picker.as_str() {
+            "fzf" => fzf_pick(&items, &preselect, first_run, &keys, &header),
+            "numbered" => numbered_pick(&items, first_run),
+            _ => skim_pick(&items, &preselect, first_run, &keys, &header),
+        };
+        let action = res.key.as_ref().and_then(|key| {
+            SETTINGS
+                .bindings
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, a)| a.as_str())
+        });
+        let commits: Vec<&CommitInfo> = res.items.iter().map(CommitInfo::from_item).collect();
+        if !matches!(action, Some("expand-topic" | "edit-query" | "open")) {
+            CommitInfo::print_skipped(&skipped);
+        }
+        match action {
+            Some("expand-topic") => {
+                preselect = expand_topics(&mut items, &res.items, &mut skipped);
+                items = group_by_topic(items);
+            }
+            Some("edit-query") => {
+                if let Some(q) = edit_query(&query) {
+                    query = q;
+                    items = load(&query);
+                    preselect.clear();
+                }
+            }
+            Some("open") => browser::show(&commits),
+            Some("copy-ref") => {
+                let refs: Vec<String> = commits.iter().map(|c| c.get_reference()).collect();
+                browser::copy_text(&refs.join("\n"));
+                std::process::exit(0);
+            }
+            Some("copy-url") => {
+                let urls: Vec<&str> = commits.iter().map(|c| c.get_url()).collect();
+                browser::copy_text(&urls.join("\n"));
+                std::process::exit(0);
+            }
+            Some(a) => return (res.items, Settings::action_method(a)),
+            None => return (res.items, None),
+        }
+        first_run = false;
+    }
+}
+
+pub fn select(items: Vec<Arc<dyn SkimItem>>) -> Vec<Arc<dyn SkimItem>> {
+    let selected: Vec<Arc<dyn SkimItem>> = match &SETTINGS.filter {
+        Some(filter) => {
+            let engine_factory =
+                AndOrEngineFactory::new(ExactOrFuzzyEngineFactory::builder().build());
+            let engine = engine_factory.create_engine_with_case(filter, CaseMatching::Smart);
+            items
+                .into_iter()
+                .filter(|item| engine.match_item(item.clone()).is_some())
+                .collect()
+        }
+        None => items,
+    };
+    if selected.is_empty() {
+        println!("No matching commits found");
+        std::process::exit(1);
+    }
+    if SETTINGS.first {
+        return selected.into_iter().take(1).collect();
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(parse_selection("1 3-4", 5), Some(vec![0, 2, 3]));
+        assert_eq!(parse_selection("2,1", 2), Some(vec![1, 0]));
+        assert_eq!(parse_selection("a", 3), Some(vec![0, 1, 2]));
+        assert_eq!(parse_selection("0", 3), None);
+        assert_eq!(parse_selection("2-4", 3), None);
+        assert_eq!(parse_selection("x", 3), None);
+    }
+}
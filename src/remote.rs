@@ -1,8 +1,30 @@
 use std::process::Command;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use json::JsonValue;
 
-use crate::SETTINGS;
+use crate::{log, REPO_INFO};
+
+// How long the detected server version is cached, in seconds
+const VERSION_CACHE_TIME: u64 = 24 * 60 * 60;
+// Failed requests are retried sooner, but not on every run
+const FAILED_VERSION_CACHE_TIME: u64 = 60 * 60;
+
+static SERVER_VERSION: OnceLock<Option<String>> = OnceLock::new();
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Parses the major and minor version from e.g. "3.5.1" or "2.16.28-12-gabcdef"
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
 
 pub enum RemoteUrl {
     SSH(String),
@@ -11,39 +33,272 @@ pub enum RemoteUrl {
 
 impl RemoteUrl {
     pub fn new(url: &str) -> Self {
+        match Self::parse(url) {
+            Some(u) => u,
+            None => panic!(
+                "Could not find the Gerrit server, remote.origin.url '{}' isn't an ssh or http url",
+                url
+            ),
+        }
+    }
+
+    pub fn parse(url: &str) -> Option<Self> {
         if url.starts_with("ssh://") {
-            Self::SSH(url.to_string())
+            Some(Self::SSH(url.to_string()))
         } else if url.starts_with("http://") || url.starts_with("https://") {
             if !url.ends_with('/') {
-                Self::HTTP(url.to_string() + "/")
+                Some(Self::HTTP(url.to_string() + "/"))
             } else {
-                Self::HTTP(url.to_string())
+                Some(Self::HTTP(url.to_string()))
             }
         } else {
-            panic!("Invalid url");
+            None
+        }
+    }
+
+    // If requests are authenticated, which needs an url ending in /a/ for HTTP
+    pub fn is_authenticated(&self) -> bool {
+        match self {
+            Self::SSH(_) => true,
+            Self::HTTP(url) => {
+                url.trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .map(|p| p.len())
+                    == Some(1)
+            }
+        }
+    }
+
+    // The SSH query results contain the url so this is only needed for HTTP
+    pub fn web_url(&self, project: &str, number: &str) -> String {
+        match self {
+            Self::SSH(_) => "".to_string(),
+            Self::HTTP(url) => {
+                let mut parts: Vec<&str> = url.trim_end_matches('/').split('/').collect();
+                // Remove the /a/ used for authenticated requests
+                if parts.len() > 3 && parts[parts.len() - 1].len() == 1 {
+                    parts.pop();
+                }
+                format!("{}/c/{}/+/{}", parts.join("/"), project, number)
+            }
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        match self {
+            Self::SSH(url) | Self::HTTP(url) => url,
+        }
+    }
+
+    // Runs a request against the server, None if it failed
+    fn request(&self, path: &str) -> Option<String> {
+        self.try_request(path).ok()
+    }
+
+    // Like request but returns the exit code of curl or ssh if it failed, None if they couldn't
+    // be run at all
+    fn try_request(&self, path: &str) -> Result<String, Option<i32>> {
+        let out = match self {
+            Self::SSH(url) => Command::new("ssh")
+                .arg(url)
+                .args(path.split_whitespace())
+                .output(),
+            Self::HTTP(url) => Command::new("curl")
+                .arg("--netrc")
+                .arg("--silent")
+                .arg("--fail")
+                .arg("--url")
+                .arg(format!("{}{}", url, path))
+                .output(),
+        }
+        .map_err(|_| None)?;
+        if !out.status.success() {
+            return Err(out.status.code());
+        }
+        let out = String::from_utf8_lossy(&out.stdout);
+        Ok(match self {
+            Self::SSH(_) => out.trim().to_string(),
+            // Skip the )]}' line
+            Self::HTTP(_) => out
+                .split_once('\n')
+                .map_or("", |(_, r)| r)
+                .trim()
+                .to_string(),
+        })
+    }
+
+    // Asks the server for its version, the exit code of curl or ssh if the request failed
+    pub fn probe_version(&self) -> Result<Option<String>, Option<i32>> {
+        let version = match self {
+            Self::SSH(_) => Some(
+                self.try_request("gerrit version")?
+                    .trim_start_matches("gerrit version ")
+                    .to_string(),
+            ),
+            Self::HTTP(_) => json::parse(&self.try_request("config/server/version")?)
+                .ok()
+                .and_then(|v| v.as_str().map(|v| v.to_string())),
+        };
+        log::debug(&format!("Detected Gerrit version {:?}", version));
+        Ok(version)
+    }
+
+    // The version of the Gerrit server, only queried once a day, or once an hour after a failure.
+    // Only the http queries depend on it.
+    pub fn server_version(&self) -> Option<&str> {
+        SERVER_VERSION
+            .get_or_init(|| {
+                let cache_file = REPO_INFO.get_version_cache_file();
+                let cache = std::fs::read_to_string(&cache_file).unwrap_or_default();
+                // An empty version records a failed request
+                if let [url, version, time] = cache.lines().collect::<Vec<&str>>()[..] {
+                    let time: u64 = time.parse().unwrap_or(0);
+                    let cache_time = if version.is_empty() {
+                        FAILED_VERSION_CACHE_TIME
+                    } else {
+                        VERSION_CACHE_TIME
+                    };
+                    if url == self.base_url() && now().saturating_sub(time) < cache_time {
+                        return Some(version.to_string()).filter(|v| !v.is_empty());
+                    }
+                }
+                let version = self.probe_version().ok().flatten();
+                let _ = std::fs::write(
+                    cache_file,
+                    format!(
+                        "{}\n{}\n{}\n",
+                        self.base_url(),
+                        version.as_deref().unwrap_or_default(),
+                        now()
+                    ),
+                );
+                version
+            })
+            .as_deref()
+    }
+
+    fn version_at_least(&self, major: u32, minor: u32) -> bool {
+        self.server_version()
+            .and_then(parse_version)
+            .is_some_and(|v| v >= (major, minor))
+    }
+
+    // Returns the name of the logged in user, the exit code of curl or ssh if the login failed
+    pub fn get_account(&self) -> Result<Option<String>, Option<i32>> {
+        match self {
+            Self::SSH(url) => {
+                // The user is part of the ssh url, so just check that the login works
+                self.try_request("gerrit version")?;
+                let host = url.trim_start_matches("ssh://");
+                Ok(host.split_once('@').map(|(user, _)| user.to_string()))
+            }
+            Self::HTTP(_) => {
+                let account =
+                    json::parse(&self.try_request("accounts/self")?).unwrap_or(JsonValue::Null);
+                Ok(account["username"]
+                    .as_str()
+                    .or(account["name"].as_str())
+                    .map(|n| n.to_string()))
+            }
+        }
+    }
+
+    // Changes with the details of their owners, without the files and commits needed to
+    // download them
+    pub fn list_changes(&self, query: &str) -> Vec<JsonValue> {
+        match self {
+            Self::SSH(_) => self
+                .request(&format!("gerrit query --format=JSON {}", query))
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|l| json::parse(l).ok())
+                .filter(|c| c["type"].as_str() != Some("stats"))
+                .collect(),
+            Self::HTTP(_) => self
+                .request(&format!(
+                    "changes/?q={}&o=DETAILED_ACCOUNTS",
+                    query.replace(' ', "+")
+                ))
+                .and_then(|out| json::parse(&out).ok())
+                .map(|data| data.members().cloned().collect())
+                .unwrap_or_default(),
         }
     }
 
     pub fn full_url(&self, query: &str) -> String {
+        self.build_url(query, false)
+    }
+
+    fn build_url(&self, query: &str, all_patchsets: bool) -> String {
         match self {
             Self::SSH(url) => {
-                let flags = "--format=JSON --current-patch-set --files --commit-message ";
+                let flags = if all_patchsets {
+                    "--format=JSON --current-patch-set --patch-sets --files --commit-message "
+                } else {
+                    "--format=JSON --current-patch-set --files --commit-message "
+                };
                 format!("{} gerrit query {} {}", url, flags, query)
             }
             Self::HTTP(url) => {
-                let fields = "o=CURRENT_REVISION&o=CURRENT_COMMIT&o=CURRENT_FILES";
+                let mut fields = if all_patchsets {
+                    "o=CURRENT_REVISION&o=ALL_REVISIONS&o=ALL_COMMITS&o=ALL_FILES".to_string()
+                } else {
+                    "o=CURRENT_REVISION&o=CURRENT_COMMIT&o=CURRENT_FILES".to_string()
+                };
+                // Computing if changes are mergeable is slow and unused, it can only be skipped
+                // from 2.15 and isn't done by default from 3.0
+                if self.version_at_least(2, 15) && !self.version_at_least(3, 0) {
+                    fields += "&o=SKIP_MERGEABLE";
+                }
                 format!("{}changes/?q={}&{}", url, query.replace(" ", "+"), fields)
             }
         }
     }
 
+    // Look up a single change by number or Change-Id. If a patch set is given it will be
+    // presented as the current one so that it's the one that gets downloaded.
+    pub fn get_change(&self, change: &str, patchset: Option<&str>) -> json::JsonValue {
+        let url = self.build_url(&format!("change:{}", change), patchset.is_some());
+        let mut commit_data = self.run_query(&url);
+        let patchset = match patchset.and_then(|p| p.parse::<u32>().ok()) {
+            Some(p) => p,
+            None => return commit_data,
+        };
+        for data in commit_data.members_mut() {
+            match self {
+                Self::SSH(_) => {
+                    let found = data["patchSets"]
+                        .members()
+                        .find(|p| p["number"].as_u32() == Some(patchset))
+                        .cloned();
+                    if let Some(p) = found {
+                        data["currentPatchSet"] = p;
+                    }
+                }
+                Self::HTTP(_) => {
+                    let found = data["revisions"]
+                        .entries()
+                        .find(|(_, r)| r["_number"].as_u32() == Some(patchset))
+                        .map(|(hash, _)| hash.to_string());
+                    if let Some(hash) = found {
+                        data["current_revision"] = hash.into();
+                    }
+                }
+            }
+        }
+        commit_data
+    }
+
     pub fn perform_query(&self, query: &str) -> json::JsonValue {
-        let url = self.full_url(query);
+        self.run_query(&self.full_url(query))
+    }
+
+    fn run_query(&self, url: &str) -> json::JsonValue {
+        let start = Instant::now();
         let commit_data = match self {
             Self::SSH(_) => {
-                if SETTINGS.debug {
-                    println!("Performing query: ssh {}", url);
-                }
+                log::debug(&log::redact(&format!("Performing query: ssh {}", url)));
                 let out = Command::new("ssh")
                     .args(url.split_whitespace())
                     .output()
@@ -65,14 +320,11 @@ impl RemoteUrl {
                     .arg(url)
                     .arg("--header")
                     .arg("Content-Type: application/json");
-                if SETTINGS.debug {
-                    println!(
-                        "Performing query: {}",
-                        full_cmd
-                            .get_args()
-                            .fold(String::new(), |acc, s| acc + s.to_str().unwrap())
-                    );
-                }
+                let args: Vec<&str> = full_cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+                log::debug(&log::redact(&format!(
+                    "Performing query: curl {}",
+                    args.join(" ")
+                )));
                 let out = full_cmd.output().expect("Failed to fetch http commit data");
                 // Need to remove the first line as it contains the magic string )]}' to prevent
                 // Cross Site Script Inclusion attacks (https://gerrit.onap.org/r/Documentation/rest-api.html#output)
@@ -93,6 +345,31 @@ impl RemoteUrl {
                 //     .to_string()
             }
         };
-        json::parse(&commit_data).unwrap_or(JsonValue::new_object())
+        log::info(&format!(
+            "Query took {}",
+            log::format_duration(start.elapsed())
+        ));
+        let start = Instant::now();
+        let data = json::parse(&commit_data).unwrap_or(JsonValue::new_object());
+        log::info(&format!(
+            "Parsing {} result(s) took {}",
+            data.len(),
+            log::format_duration(start.elapsed())
+        ));
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("3.5.1"), Some((3, 5)));
+        assert_eq!(parse_version("2.16.28-12-gabcdef"), Some((2, 16)));
+        assert_eq!(parse_version("3.10"), Some((3, 10)));
+        assert_eq!(parse_version("3"), None);
+        assert_eq!(parse_version(""), None);
     }
 }
@@ -1,5 +1,7 @@
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+use crate::manifest::Manifest;
 use crate::remote::RemoteUrl;
 
 #[derive(PartialEq, Eq)]
@@ -12,6 +14,7 @@ pub struct RepoInfo {
     pub remote_url: RemoteUrl,
     pub repo_type: RepoType,
     pub project_name: String,
+    pub manifest: Manifest,
 }
 
 impl RepoInfo {
@@ -19,34 +22,23 @@ impl RepoInfo {
         let repo_type = Self::get_repo_type();
         let remote_url = Self::guess_remote(&repo_type);
         let project_name = Self::get_project_name(&remote_url);
+        let manifest = if repo_type == RepoType::Repo {
+            Manifest::read(&Self::get_repo_toplevel())
+        } else {
+            Manifest::default()
+        };
 
         return RepoInfo {
             remote_url: RemoteUrl::new(&remote_url),
             repo_type,
             project_name,
+            manifest,
         };
     }
 
-    fn get_repo_manifest_dir() -> String {
-        let out = Command::new("repo")
-            .arg("list")
-            .arg("manifest.git")
-            .arg("--relative-to=.")
-            .output()
-            .expect("Failed to run");
-        std::str::from_utf8(&out.stdout)
-            .unwrap()
-            .trim()
-            .to_string()
-            .split_whitespace()
-            .next()
-            .unwrap_or("")
-            .to_string()
-    }
-
-    fn guess_remote(repo_type: &RepoType) -> String {
-        let manifest_dir = Self::get_repo_manifest_dir();
-        let git_dir = if *repo_type == RepoType::Git || manifest_dir.is_empty() {
+    pub fn guess_remote(repo_type: &RepoType) -> String {
+        let manifest_dir = Self::get_repo_toplevel() + "/.repo/manifests";
+        let git_dir = if *repo_type == RepoType::Git {
             "."
         } else {
             &manifest_dir[..]
@@ -58,7 +50,7 @@ impl RepoInfo {
         if parts.len() > 3 && parts[3].len() == 1 {
             return parts[..4].join("/");
         }
-        parts[..3].join("/")
+        parts[..parts.len().min(3)].join("/")
     }
 
     fn get_project_name(url: &str) -> String {
@@ -87,26 +79,128 @@ impl RepoInfo {
         std::str::from_utf8(&out.stdout).unwrap().trim().to_string()
     }
 
-    fn get_repo_type() -> RepoType {
-        let is_repo = Command::new("repo")
+    pub fn get_project_path(&self, project: &str) -> String {
+        if self.repo_type == RepoType::Git {
+            return ".".to_string();
+        }
+        if let Some(p) = self.manifest.get_project(project) {
+            let cwd = std::env::current_dir().unwrap_or_default();
+            return match Path::new(&p.path).strip_prefix(&cwd) {
+                Ok(rel) if rel.as_os_str().is_empty() => ".".to_string(),
+                Ok(rel) => rel.to_str().unwrap().to_string(),
+                Err(_) => p.path.clone(),
+            };
+        }
+        // Fall back to asking repo if it's installed
+        let path = Command::new("repo")
+            .arg("list")
+            .arg("--path-only")
+            .arg("--relative-to=.")
+            .arg(project)
+            .output()
+            .map(|out| std::str::from_utf8(&out.stdout).unwrap().trim().to_string())
+            .unwrap_or_default();
+        if path.is_empty() {
+            ".".to_string()
+        } else {
+            path
+        }
+    }
+
+    // Directory where grt keeps its own files
+    fn get_grt_dir(&self) -> String {
+        if self.repo_type == RepoType::Git {
+            let out = Command::new("git")
+                .arg("rev-parse")
+                .arg("--path-format=absolute")
+                .arg("--git-common-dir")
+                .output()
+                .expect("Failed to run 'git'");
+            std::str::from_utf8(&out.stdout).unwrap().trim().to_string()
+        } else {
+            Self::get_repo_toplevel() + "/.repo"
+        }
+    }
+
+    pub fn get_history_file(&self) -> String {
+        self.get_grt_dir() + "/grt-history"
+    }
+
+    pub fn get_log_file(&self) -> String {
+        self.get_grt_dir() + "/grt.log"
+    }
+
+    pub fn get_version_cache_file(&self) -> String {
+        self.get_grt_dir() + "/grt-server-version"
+    }
+
+    pub fn get_completion_cache_file(&self) -> String {
+        self.get_grt_dir() + "/grt-completions"
+    }
+
+    // The closest parent directory containing a .repo directory with a manifest
+    fn find_repo_toplevel() -> Option<PathBuf> {
+        let cwd = std::env::current_dir().ok()?;
+        cwd.ancestors()
+            .find(|dir| {
+                let repo_dir = dir.join(".repo");
+                repo_dir.join("manifest.xml").exists() || repo_dir.join("manifests").is_dir()
+            })
+            .map(|dir| dir.to_path_buf())
+    }
+
+    fn get_repo_toplevel() -> String {
+        Self::find_repo_toplevel()
+            .map(|dir| dir.to_str().unwrap().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn get_toplevel() -> String {
+        let out = Command::new("git")
+            .arg("rev-parse")
             .arg("--show-toplevel")
-            .stdout(Stdio::null())
+            .output()
+            .expect("Failed to run 'git'");
+        std::str::from_utf8(&out.stdout).unwrap().trim().to_string()
+    }
+
+    // The name of the remote branch the current branch is tracking, e.g. main for origin/main
+    pub fn get_upstream_branch() -> Option<String> {
+        let out = Command::new("git")
+            .arg("rev-parse")
+            .arg("--abbrev-ref")
+            .arg("--symbolic-full-name")
+            .arg("@{upstream}")
             .stderr(Stdio::null())
-            .status()
-            .expect("Failed to run 'repo'")
-            .success();
+            .output()
+            .ok()?;
+        let upstream = std::str::from_utf8(&out.stdout).ok()?.trim();
+        upstream
+            .split_once('/')
+            .map(|(_, branch)| branch.to_string())
+    }
+
+    pub fn detect_repo_type() -> Option<RepoType> {
+        let is_repo = Self::find_repo_toplevel().is_some();
         let is_git = Command::new("git")
             .arg("rev-parse")
             .arg("--is-inside-work-tree")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .status()
-            .expect("Failed to run 'git'")
-            .success();
-        return match (is_repo, is_git) {
-            (true, _) => RepoType::Repo,
-            (_, true) => RepoType::Git,
-            (false, false) => panic!("Must be run in a repo"),
-        };
+            .map(|s| s.success())
+            .unwrap_or(false);
+        match (is_repo, is_git) {
+            (true, _) => Some(RepoType::Repo),
+            (_, true) => Some(RepoType::Git),
+            (false, false) => None,
+        }
+    }
+
+    fn get_repo_type() -> RepoType {
+        match Self::detect_repo_type() {
+            Some(t) => t,
+            None => panic!("Must be run inside a git repository or a repo workspace"),
+        }
     }
 }
@@ -1,34 +1,58 @@
-use getopts::Options;
 use std::env;
 
+use crate::cli;
+use crate::log;
+use crate::repo_info::{RepoInfo, RepoType};
 use crate::REPO_INFO;
 
 pub struct Settings {
-    pub method: String,
     pub select_all: bool,
     pub query: String,
-    pub debug: bool,
+    pub verbose: bool,
+    pub jobs: usize,
     pub show_parent: bool,
-    only_open: bool,
-    options: getopts::Options,
+    pub dry_run: bool,
+    pub yes: bool,
+    pub script: Option<String>,
+    pub first: bool,
+    pub filter: Option<String>,
+    change: Option<String>,
+    new_branch: bool,
+    pub branch_name: Option<String>,
+    worktree: bool,
+    pub worktree_path: Option<String>,
+    pub stash: bool,
+    pub allow_dirty: bool,
+    pub patch_dir: String,
+    pub with_topic: bool,
+    pub direct_change: Option<(String, Option<String>)>,
+    pub print_url: bool,
+    pub copy_url: bool,
+    pub bindings: Vec<(String, String)>,
+    pub height: String,
+    pub reverse: bool,
+    pub preview_window: String,
+    pub color: Option<String>,
+    pub prompt: String,
+    pub multi: bool,
+    pub picker: String,
+    pub args: Vec<String>,
+    status: String,
+    owner: Option<String>,
+    reviewer: Option<String>,
+    branch: Option<String>,
+    upstream: bool,
+    topic: Option<String>,
+    since: Option<String>,
+    mine: bool,
+    wip: Option<bool>,
+    command: &'static cli::Command,
 }
 
 impl Settings {
     pub fn new() -> Self {
-        let mut opts = Options::new();
-        opts.optflag("h", "help", "Print this menu");
-        opts.optflag("c", "closed", "Include closed commits");
-        opts.optflag(
-            "o",
-            "open",
-            "Don't include closed commits (default, will override -c if set)",
-        );
-        opts.optflag("a", "all", "pre-select all commits");
-        opts.optflag("p",
-            "show-parent",
-            "Show info about parent commit in preview even in ssh (This will lead to additional queries)");
-        opts.optflag("", "debug", "Print debug information while running");
-
+        log::init();
+        let opts = cli::all_options();
         let matches_env = opts
             .parse(
                 env::var("GRT_ARGS")
@@ -36,74 +60,262 @@ impl Settings {
                     .split(' '),
             )
             .expect("Failed to parse env args");
-        let matches_cmd = opts
-            .parse(&env::args().collect::<Vec<String>>()[1..])
-            .expect("Failed to parse cmd args");
+        let matches_cmd = match opts.parse(&env::args().collect::<Vec<String>>()[1..]) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("{}", e);
+                println!();
+                cli::print_help(None, 1);
+            }
+        };
+
+        let command = match matches_cmd.free.first() {
+            Some(name) => match cli::find_command(name) {
+                Some(c) => c,
+                None => {
+                    println!("Unsupported operation '{}'", name);
+                    println!();
+                    cli::print_help(None, 1);
+                }
+            },
+            None if matches_cmd.opt_present("help") => cli::print_help(None, 0),
+            None => {
+                println!("Must add a command");
+                println!();
+                cli::print_help(None, 1);
+            }
+        };
+        if command.method == "Help" {
+            match matches_cmd.free.get(1) {
+                Some(name) => match cli::find_command(name) {
+                    Some(c) => cli::print_help(Some(c), 0),
+                    None => {
+                        println!("Unsupported operation '{}'", name);
+                        println!();
+                        cli::print_help(None, 1);
+                    }
+                },
+                None => cli::print_help(None, 0),
+            }
+        }
+        if matches_cmd.opt_present("help") {
+            cli::print_help(Some(command), 0);
+        }
+        // Options in GRT_ARGS apply to all commands, but on the command line only the ones
+        // of the command are accepted
+        if let Some(o) = cli::OPTIONS
+            .iter()
+            .find(|o| !command.has(o) && matches_cmd.opt_present(o.long))
+        {
+            println!("--{} can't be used with {}", o.long, command.name);
+            println!();
+            cli::print_help(Some(command), 1);
+        }
 
         let mut s = Self {
-            method: "".to_string(),
             query: "limit:200 ".to_string(),
             select_all: false,
-            debug: false,
-            only_open: true,
+            verbose: false,
+            jobs: 1,
+            status: "open".to_string(),
+            owner: None,
+            reviewer: None,
+            branch: None,
+            upstream: false,
+            topic: None,
+            since: None,
+            mine: false,
+            wip: None,
             show_parent: false,
-            options: opts,
+            dry_run: false,
+            yes: false,
+            script: None,
+            first: false,
+            filter: None,
+            change: None,
+            new_branch: false,
+            branch_name: None,
+            worktree: false,
+            worktree_path: None,
+            stash: false,
+            allow_dirty: false,
+            patch_dir: "patches".to_string(),
+            with_topic: false,
+            direct_change: None,
+            print_url: false,
+            copy_url: false,
+            bindings: [
+                ("ctrl-t", "expand-topic"),
+                ("ctrl-b", "open"),
+                ("ctrl-y", "copy-ref"),
+                ("ctrl-o", "checkout"),
+                ("ctrl-x", "cherry-pick"),
+                ("ctrl-q", "edit-query"),
+            ]
+            .iter()
+            .map(|(k, a)| (k.to_string(), a.to_string()))
+            .collect(),
+            height: "50%".to_string(),
+            reverse: false,
+            preview_window: "right:50%".to_string(),
+            color: None,
+            prompt: "> ".to_string(),
+            multi: true,
+            picker: if env::var("TERM").map(|t| t == "dumb").unwrap_or(false) {
+                "numbered".to_string()
+            } else {
+                "skim".to_string()
+            },
+            args: matches_cmd.free[1..].to_vec(),
+            command,
         };
 
         s.parse_args(&matches_env);
         s.parse_args(&matches_cmd);
 
-        if matches_cmd.free.is_empty() {
-            println!("Must add a command, valid options are 'checkout', 'co', 'cherry-pick', 'cp'");
+        // Defaults from GRT_ARGS are ignored by the commands they don't apply to
+        let method = command.method;
+        let given = |names: &[&str]| names.iter().any(|n| matches_cmd.opt_present(n));
+        if given(&["local-branch", "review-branch"]) && !s.new_branch(method) {
+            println!("--local-branch and --review-branch can only be used with checkout and fetch");
             println!();
             s.print_usage();
         }
-        s.method = match matches_cmd.free[0].as_str() {
-            "checkout" | "co" => "Checkout".to_string(),
-            "cherry-pick" | "cp" => "Cherry-Pick".to_string(),
-            op => {
-                println!("Unsupported operation '{}'", op);
+        if given(&["worktree", "new-worktree"]) {
+            if method != "Checkout" {
+                println!("--worktree and --new-worktree can only be used with checkout");
                 println!();
                 s.print_usage();
             }
-        };
-        s.create_query(&matches_cmd.free[1..].join(" "));
-        if s.debug {
+            if REPO_INFO.repo_type != RepoType::Git {
+                println!("Worktrees are only supported in git repositories");
+                std::process::exit(1);
+            }
+        }
+        if matches_cmd.free.len() == 2 {
+            s.direct_change = Self::parse_change(&matches_cmd.free[1]);
+        }
+        // Commands without a query should work even if the repository info can't be found
+        if command.takes_query() {
+            s.create_query(&matches_cmd.free[1..].join(" "));
+        }
+        log::debug(&format!(
+            "Env args: '{}'",
+            env::var("GRT_ARGS").unwrap_or_else(|_| "".to_string())
+        ));
+        log::debug(&format!(
+            "Cmd args: '{}'",
+            env::args().collect::<Vec<String>>().join(" ")
+        ));
+        log::debug(&format!("Query: '{}'", s.query));
+
+        s
+    }
+
+    fn parse_method(method: &str) -> Option<&'static str> {
+        cli::find_command(method).map(|c| c.method)
+    }
+
+    fn is_valid_action(action: &str) -> bool {
+        match cli::find_command(action) {
+            Some(c) => c.uses_picker(),
+            None => ["expand-topic", "edit-query", "copy-ref", "copy-url"].contains(&action),
+        }
+    }
+
+    // Returns the method for picker actions that should download the selected commits
+    pub fn action_method(action: &str) -> Option<&'static str> {
+        match Self::parse_method(action) {
+            Some("Open") => None,
+            m => m,
+        }
+    }
+
+    // The command given on the command line, picker actions can run another one
+    pub fn method(&self) -> &'static str {
+        self.command.method
+    }
+
+    pub fn new_branch(&self, method: &str) -> bool {
+        self.new_branch && (method == "Checkout" || method == "Fetch")
+    }
+
+    pub fn worktree(&self, method: &str) -> bool {
+        self.worktree && method == "Checkout" && REPO_INFO.repo_type == RepoType::Git
+    }
+
+    // Tells about the options that don't apply when a picker action runs another command
+    pub fn warn_ignored(&self, method: &str) {
+        if self.new_branch && !self.new_branch(method) {
             println!(
-                "Env args: '{}'",
-                env::var("GRT_ARGS").unwrap_or_else(|_| "".to_string())
+                "Ignoring --local-branch and --review-branch for {}",
+                method.to_lowercase()
             );
+        }
+        if self.worktree && !self.worktree(method) {
             println!(
-                "Cmd args: '{}'",
-                env::args().collect::<Vec<String>>().join(" ")
+                "Ignoring --worktree and --new-worktree for {}",
+                method.to_lowercase()
             );
-            println!("Query: '{}'", s.query);
         }
-
-        s
     }
 
     fn print_usage(&self) -> ! {
-        let brief = format!(
-            "Usage: {} [options] checkout|co|cherry-pick|cp [query]",
-            env::args().next().unwrap()
-        );
-        print!("{}", self.options.usage(&brief));
-        println!("\nThe options can be set either on command line or through");
-        println!("the env var GRT_ARGS, anything set on command line will");
-        println!("override what's set in the environment.");
-        std::process::exit(1);
+        cli::print_help(Some(self.command), 1);
     }
 
     pub fn parse_args(&mut self, matches: &getopts::Matches) {
-        if matches.opt_present("help") {
-            self.print_usage();
+        if matches.opt_present("closed") {
+            self.status = "any".to_string();
         }
-
-        if !self.only_open && matches.opt_present("open") {
-            self.only_open = true;
-        } else if matches.opt_present("closed") {
-            self.only_open = false;
+        if matches.opt_present("merged") {
+            self.status = "merged".to_string();
+        }
+        if matches.opt_present("abandoned") {
+            self.status = "abandoned".to_string();
+        }
+        if let Some(status) = matches.opt_str("status") {
+            if !["open", "merged", "abandoned", "closed", "any"].contains(&status.as_str()) {
+                println!("Unsupported status '{}'", status);
+                println!();
+                self.print_usage();
+            }
+            self.status = status;
+        }
+        if matches.opt_present("open") {
+            self.status = "open".to_string();
+        }
+        if let Some(owner) = matches.opt_str("owner") {
+            self.owner = Some(owner);
+        }
+        if matches.opt_present("mine") {
+            self.mine = true;
+        }
+        if let Some(reviewer) = matches.opt_str("reviewer") {
+            self.reviewer = Some(reviewer);
+        }
+        if let Some(branch) = matches.opt_str("branch") {
+            self.branch = Some(branch);
+        }
+        if matches.opt_present("upstream") {
+            self.upstream = true;
+        }
+        if let Some(topic) = matches.opt_str("topic") {
+            self.topic = Some(topic);
+        }
+        if let Some(since) = matches.opt_str("since") {
+            if !Self::is_valid_age(&since) {
+                println!("Invalid age '{}'", since);
+                println!();
+                self.print_usage();
+            }
+            self.since = Some(since);
+        }
+        if matches.opt_present("wip") {
+            self.wip = Some(true);
+        }
+        if matches.opt_present("no-wip") {
+            self.wip = Some(false);
         }
 
         if matches.opt_present("all") {
@@ -112,18 +324,263 @@ impl Settings {
         if matches.opt_present("show-parent") {
             self.show_parent = true;
         }
-        if matches.opt_present("debug") {
-            self.debug = true;
+        if matches.opt_present("dry-run") {
+            self.dry_run = true;
+        }
+        if let Some(script) = matches.opt_str("script") {
+            self.dry_run = true;
+            self.script = Some(script);
+        }
+        if matches.opt_present("first") {
+            self.first = true;
+        }
+        if let Some(change) = matches.opt_str("change") {
+            self.change = Some(change);
+        }
+        if let Some(filter) = matches.opt_str("filter") {
+            self.filter = Some(filter);
+        }
+        if let Some(name) = matches.opt_str("local-branch") {
+            self.new_branch = true;
+            self.branch_name = Some(name);
+        }
+        if matches.opt_present("review-branch") {
+            self.new_branch = true;
+            self.branch_name = None;
+        }
+        if let Some(path) = matches.opt_str("worktree") {
+            self.worktree = true;
+            self.worktree_path = Some(path);
+        }
+        if matches.opt_present("new-worktree") {
+            self.worktree = true;
+            self.worktree_path = None;
+        }
+        if matches.opt_present("stash") {
+            self.stash = true;
+        }
+        if matches.opt_present("allow-dirty") {
+            self.allow_dirty = true;
+        }
+        if let Some(dir) = matches.opt_str("patch-dir") {
+            self.patch_dir = dir;
+        }
+        if matches.opt_present("with-topic") {
+            self.with_topic = true;
+        }
+        if matches.opt_present("print-url") {
+            self.print_url = true;
+        }
+        if matches.opt_present("copy-url") {
+            self.copy_url = true;
+        }
+        for bind in matches.opt_strs("bind") {
+            match bind.split_once(':') {
+                Some((key, action)) if Self::is_valid_action(action) => {
+                    self.bindings.retain(|(k, _)| k != key);
+                    self.bindings.push((key.to_string(), action.to_string()));
+                }
+                _ => {
+                    println!("Invalid key binding '{}'", bind);
+                    println!();
+                    self.print_usage();
+                }
+            }
+        }
+        if let Some(height) = matches.opt_str("height") {
+            if height.trim_end_matches('%').parse::<u16>().is_err() {
+                println!("Invalid height '{}'", height);
+                println!();
+                self.print_usage();
+            }
+            self.height = height;
+        }
+        if matches.opt_present("reverse") {
+            self.reverse = true;
+        }
+        if let Some(preview_window) = matches.opt_str("preview-window") {
+            self.preview_window = preview_window;
+        }
+        if let Some(color) = matches.opt_str("color") {
+            self.color = Some(color);
+        }
+        if let Some(prompt) = matches.opt_str("prompt") {
+            self.prompt = prompt;
+        }
+        if let Some(picker) = matches.opt_str("picker") {
+            if !["skim", "fzf", "numbered"].contains(&picker.as_str()) {
+                println!("Unsupported picker '{}'", picker);
+                println!();
+                self.print_usage();
+            }
+            self.picker = picker;
+        }
+        if matches.opt_present("no-multi") {
+            self.multi = false;
+        }
+        if matches.opt_present("yes") {
+            self.yes = true;
+        }
+        if let Some(jobs) = matches.opt_str("jobs") {
+            match jobs.parse::<usize>() {
+                Ok(j) if j > 0 => self.jobs = j,
+                _ => {
+                    println!("Invalid number of jobs '{}'", jobs);
+                    println!();
+                    self.print_usage();
+                }
+            }
+        }
+        let level = if matches.opt_present("debug") {
+            log::DEBUG
+        } else {
+            matches.opt_count("verbose")
+        };
+        if level > 0 {
+            self.verbose = true;
+            log::set_level(level);
         }
     }
 
+    // Accepts a change number, a Change-Id or a change URL like https://host/c/project/+/123/4
+    // and returns the change and patch set, if any
+    fn parse_change(arg: &str) -> Option<(String, Option<String>)> {
+        let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if is_number(arg) {
+            return Some((arg.to_string(), None));
+        }
+        if arg.len() == 41
+            && arg.starts_with('I')
+            && arg[1..].chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Some((arg.to_string(), None));
+        }
+        if !arg.starts_with("http://") && !arg.starts_with("https://") {
+            return None;
+        }
+        let path = match arg.rsplit_once("/+/") {
+            Some((_, path)) => path,
+            None => arg.rsplit_once("/c/")?.1,
+        };
+        let mut parts = path.split('/');
+        let change = parts.next().filter(|p| is_number(p))?;
+        let patchset = parts.next().filter(|p| is_number(p));
+        Some((change.to_string(), patchset.map(|p| p.to_string())))
+    }
+
+    // Ages like 2d or 12hours as understood by Gerrit
+    fn is_valid_age(age: &str) -> bool {
+        let unit = age.trim_start_matches(|c: char| c.is_ascii_digit());
+        unit.len() < age.len()
+            && [
+                "s", "sec", "second", "seconds", "m", "min", "minute", "minutes", "h", "hr",
+                "hour", "hours", "d", "day", "days", "w", "week", "weeks", "mon", "month",
+                "months", "y", "year", "years",
+            ]
+            .contains(&unit)
+    }
+
     fn create_query(&mut self, query: &str) {
-        if self.only_open {
-            self.query += "status:open ";
+        if self.status != "any" {
+            self.query += format!("status:{} ", self.status).as_str();
+        }
+        if let Some(owner) = &self.owner {
+            self.query += format!("owner:{} ", owner).as_str();
+        }
+        if self.mine {
+            self.query += "owner:self ";
+        }
+        if let Some(reviewer) = &self.reviewer {
+            self.query += format!("reviewer:{} ", reviewer).as_str();
+        }
+        if let Some(branch) = &self.branch {
+            self.query += format!("branch:{} ", branch).as_str();
+        }
+        if self.upstream {
+            match RepoInfo::get_upstream_branch() {
+                Some(b) => self.query += format!("branch:{} ", b).as_str(),
+                None => {
+                    println!("The current branch has no upstream, use --branch NAME instead");
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(topic) = &self.topic {
+            self.query += format!("topic:{} ", topic).as_str();
+        }
+        if let Some(since) = &self.since {
+            self.query += format!("-age:{} ", since).as_str();
+        }
+        match self.wip {
+            Some(true) => self.query += "is:wip ",
+            Some(false) => self.query += "-is:wip ",
+            None => {}
         }
         if !REPO_INFO.project_name.is_empty() {
             self.query += format!("project:{} ", REPO_INFO.project_name).as_str();
         }
+        if let Some(change) = &self.change {
+            self.query += format!("change:{} ", change).as_str();
+        }
         self.query += query;
     }
+
+    pub fn topic_query(&self, topic: &str, branch: &str) -> String {
+        format!("status:open topic:{} branch:{}", topic, branch)
+    }
+
+    pub fn modifies_worktree(&self, method: &str) -> bool {
+        !self.worktree(method) && method != "Fetch" && method != "Patch"
+    }
+
+    pub fn non_interactive(&self) -> bool {
+        self.first || self.change.is_some() || self.filter.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_age() {
+        assert!(Settings::is_valid_age("2d"));
+        assert!(Settings::is_valid_age("12hours"));
+        assert!(!Settings::is_valid_age("d"));
+        assert!(!Settings::is_valid_age("2"));
+        assert!(!Settings::is_valid_age("2 days"));
+    }
+
+    #[test]
+    fn test_parse_change() {
+        assert_eq!(
+            Settings::parse_change("12345"),
+            Some(("12345".to_string(), None))
+        );
+        assert_eq!(
+            Settings::parse_change("I95eda6180426529e4c959c60a7a575751a00fc20"),
+            Some((
+                "I95eda6180426529e4c959c60a7a575751a00fc20".to_string(),
+                None
+            ))
+        );
+        assert_eq!(
+            Settings::parse_change("https://gerrit/c/dummy/+/41/2"),
+            Some(("41".to_string(), Some("2".to_string())))
+        );
+        assert_eq!(
+            Settings::parse_change("https://gerrit/c/dummy/+/41/"),
+            Some(("41".to_string(), None))
+        );
+        assert_eq!(
+            Settings::parse_change("https://gerrit/c/dummy/+/41/2/README.md"),
+            Some(("41".to_string(), Some("2".to_string())))
+        );
+        assert_eq!(
+            Settings::parse_change("https://gerrit/#/c/41/"),
+            Some(("41".to_string(), None))
+        );
+        assert_eq!(Settings::parse_change("owner:self"), None);
+        assert_eq!(Settings::parse_change("Ifix"), None);
+    }
 }